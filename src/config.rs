@@ -0,0 +1,207 @@
+use rage::r_core::env::Team;
+use rage::r_core::controller::strategy::{self, DEFAULT_STRATEGY};
+use serde::{Serialize, Deserialize};
+
+const DEFAULT_GET_URL: &str = "http://localhost:3000";
+const DEFAULT_POST_URL_A: &str = "http://localhost:4000";
+const DEFAULT_POST_URL_B: &str = "http://localhost:5000";
+const DEFAULT_OPPONENT: &str = "unknown";
+
+const USAGE: &str = "Usage: rage [options]
+
+Options:
+    --config FILE       read settings from a JSON config file, flags override it
+    --team A|B          team to play as
+    --map A|B|C|FILE    map to load, either a bundled map or a path to a map file
+    --get-url URL       url to poll for game data
+    --post-url URL      url to post actions to, defaults to the team's port
+    --tick-rate HZ      maximum number of updates per second
+    --controller NAME   strategy to run: {controllers}
+    --record FILE       write every frame and action of the match to a replay file
+    --data-dir DIR      keep what the predictor learns in DIR, loaded at start and
+                        saved when the match ends. Replays load it but never save
+    --opponent NAME     opponent the learned data is kept for, defaults to unknown
+    --rules FILE        read bullet speed, lifetime, bounces, tank size, tank speed,
+                        turn rate, fire cooldown and arena size from a JSON file
+                        instead of the defaults. Without it the bullet speed is taken
+                        from observed projectiles
+    --replay FILE       run the controller over a replay file instead of connecting,
+                        printing every action that differs from the recorded one.
+                        Combined with --record the replayed run is saved as well.
+                        The recorded rules are used unless --rules is given
+    --help              print this message
+
+Team and map are asked for on stdin when not given.";
+
+//The help text, listing the strategies that can be picked
+pub fn usage() -> String {
+    let controllers = strategy::strategy_names()
+        .map(|name| if name == DEFAULT_STRATEGY { format!("{} (default)", name) } else { name.to_string() })
+        .collect::<Vec<_>>()
+        .join(", ");
+    USAGE.replace("{controllers}", &controllers)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub team: Option<Team>,
+    pub map: Option<String>,
+    pub get_url: String,
+    pub post_url: Option<String>,
+    pub tick_rate: Option<f32>,
+    pub controller: String,
+    pub record: Option<String>,
+    pub replay: Option<String>,
+    pub data_dir: Option<String>,
+    pub opponent: String,
+    pub rules: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            team: None,
+            map: None,
+            get_url: DEFAULT_GET_URL.to_string(),
+            post_url: None,
+            tick_rate: None,
+            controller: DEFAULT_STRATEGY.to_string(),
+            record: None,
+            replay: None,
+            data_dir: None,
+            opponent: DEFAULT_OPPONENT.to_string(),
+            rules: None,
+        }
+    }
+}
+
+impl Config {
+    pub fn from_file(path: &str) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|err| format!("could not read config {}: {}", path, err))?;
+        serde_json::from_str(&text)
+            .map_err(|err| format!("could not parse config {}: {}", path, err))
+    }
+
+    //Flags override the config file given with --config, which overrides the defaults.
+    //Returns None when --help was passed.
+    pub fn from_args<I: Iterator<Item=String>>(args: I) -> Result<Option<Self>, String> {
+        let mut flags = vec![];
+        let mut args = args;
+        while let Some(flag) = args.next() {
+            if flag == "--help" || flag == "-h" {
+                return Ok(None);
+            }
+            if !flag.starts_with("--") {
+                return Err(format!("unexpected argument {}", flag));
+            }
+            let value = args.next().ok_or_else(|| format!("missing value for {}", flag))?;
+            flags.push((flag, value));
+        }
+
+        let mut config = match flags.iter().find(|(flag, _)| flag == "--config") {
+            Some((_, path)) => Self::from_file(path)?,
+            None => Self::default()
+        };
+        for (flag, value) in flags {
+            match flag.as_str() {
+                "--config" => {}
+                "--team" => config.team = Some(parse_team(&value)?),
+                "--map" => config.map = Some(value),
+                "--get-url" => config.get_url = value,
+                "--post-url" => config.post_url = Some(value),
+                "--tick-rate" => {
+                    let rate = value.parse::<f32>()
+                        .map_err(|_| format!("invalid tick rate {}", value))?;
+                    config.tick_rate = Some(rate);
+                }
+                "--controller" => config.controller = value,
+                "--record" => config.record = Some(value),
+                "--replay" => config.replay = Some(value),
+                "--data-dir" => config.data_dir = Some(value),
+                "--opponent" => config.opponent = value,
+                "--rules" => config.rules = Some(value),
+                _ => return Err(format!("unknown option {}", flag))
+            }
+        }
+        if !strategy::strategy_names().any(|name| name == config.controller) {
+            return Err(format!("unknown controller {}", config.controller));
+        }
+        if let Some(rate) = config.tick_rate {
+            if !(rate > 0f32) {
+                return Err(format!("invalid tick rate {}", rate));
+            }
+        }
+        Ok(Some(config))
+    }
+
+    pub fn post_url(&self, team: Team) -> String {
+        match &self.post_url {
+            Some(url) => url.clone(),
+            None => match team {
+                Team::A => DEFAULT_POST_URL_A.to_string(),
+                Team::B => DEFAULT_POST_URL_B.to_string(),
+            }
+        }
+    }
+}
+
+pub fn parse_team(input: &str) -> Result<Team, String> {
+    match input {
+        "A" | "a" => Ok(Team::A),
+        "B" | "b" => Ok(Team::B),
+        _ => Err(format!("unknown team {}", input))
+    }
+}
+
+//A, B and C stand for the bundled maps, anything else is a path
+pub fn map_path(input: &str) -> String {
+    match input {
+        "A" | "B" | "C" => format!("./maps/Map{}.json", input),
+        path => path.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Option<Config>, String> {
+        Config::from_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn flags_override_the_config_file() {
+        let path = std::env::temp_dir().join(format!("rage_config_{}.json", std::process::id()));
+        std::fs::write(&path, r#"{"team": "B", "tick_rate": 20, "opponent": "filed"}"#).unwrap();
+        let path = path.to_str().unwrap().to_string();
+        let config = parse(&["--opponent", "flagged", "--config", &path]).unwrap().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(config.team, Some(Team::B));
+        assert_eq!(config.tick_rate, Some(20f32));
+        assert_eq!(config.opponent, "flagged");
+        assert_eq!(config.get_url, DEFAULT_GET_URL);
+        assert_eq!(config.post_url(Team::B), DEFAULT_POST_URL_B);
+    }
+
+    #[test]
+    fn help_stops_parsing() {
+        assert!(parse(&["--help"]).unwrap().is_none());
+        assert!(parse(&["--team", "A", "-h"]).unwrap().is_none());
+        let usage = usage();
+        assert!(strategy::strategy_names().all(|name| usage.contains(name)));
+        assert!(usage.contains(&format!("{} (default)", DEFAULT_STRATEGY)));
+    }
+
+    #[test]
+    fn rejects_bad_arguments() {
+        assert_eq!(parse(&["--colour", "red"]).unwrap_err(), "unknown option --colour");
+        assert_eq!(parse(&["--team"]).unwrap_err(), "missing value for --team");
+        assert_eq!(parse(&["team"]).unwrap_err(), "unexpected argument team");
+        assert_eq!(parse(&["--tick-rate", "fast"]).unwrap_err(), "invalid tick rate fast");
+        assert_eq!(parse(&["--tick-rate", "0"]).unwrap_err(), "invalid tick rate 0");
+        assert_eq!(parse(&["--controller", "sniper"]).unwrap_err(), "unknown controller sniper");
+        assert_eq!(parse(&["--team", "C"]).unwrap_err(), "unknown team C");
+    }
+}
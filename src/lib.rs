@@ -0,0 +1,3 @@
+#![cfg_attr(test, feature(test))]
+
+pub mod r_core;
\ No newline at end of file
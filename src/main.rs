@@ -1,120 +1,181 @@
-mod r_core;
+mod config;
 
 use reqwest;
-use std::io::Read;
-use std::collections::HashMap;
-use reqwest::Url;
-use reqwest::header::{CONTENT_TYPE, HeaderMap};
-use serde::{Serialize, Deserialize};
-use crate::r_core::predictor::knn::LinearSearcher;
-use crate::r_core::controller::controller::Controller;
-use crate::r_core::env::{Env, Team};
+use rage::r_core::controller::controller::Controller;
+use rage::r_core::controller::strategy;
+use rage::r_core::env::Env;
+use rage::r_core::map::map::Map;
+use rage::r_core::error::Error;
+use rage::r_core::protocol::GameData;
+use rage::r_core::replay::{self, Record, Recorder};
+use rage::r_core::predictor::predictor;
+use rage::r_core::rules::GameRules;
 use std::sync::{Arc, Mutex};
-use r_core::map::map::Map;
-use crate::r_core::map::map::Shape::{Rect, RotRect, Circle};
-use crate::r_core::math::vec2::Vec2;
-use crate::r_core::state::Bullet;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use text_io::read;
+use crate::config::Config;
 
-const GET_URL: &str = "http://localhost:3000";
-const POST_URL_A: &str = "http://localhost:4000";
-const POST_URL_B: &str = "http://localhost:5000";
+const MIN_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(5);
+//Learned data is also saved this often in case the bot gets killed mid match
+const SAVE_INTERVAL: Duration = Duration::from_secs(30);
 
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let config = match Config::from_args(std::env::args().skip(1)) {
+        Ok(Some(config)) => config,
+        Ok(None) => {
+            println!("{}", config::usage());
+            return Ok(());
+        }
+        Err(err) => {
+            eprintln!("{}\n\n{}", err, config::usage());
+            std::process::exit(2);
+        }
+    };
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub enum DataType {
-    Str(String),
-    Bool(bool),
-}
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct Projectile {
-    x: String,
-    y: String,
-    vx: i32,
-    vy: i32,
-}
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct TankData {
-    pub x: String,
-    pub y: String,
-    pub r: String,
-    pub can_fire: bool,
-}
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct GameData {
-    tankA: TankData,
-    tankB: TankData,
-    projectiles: HashMap<String, Projectile>,
-}
+    let rules = match &config.rules {
+        Some(path) => Some(GameRules::from_file(path)
+            .map_err(|err| format!("could not read rules {}: {}", path, err))?),
+        None => None
+    };
+    if let Some(path) = &config.replay {
+        return run_replay(path, &config, rules);
+    }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let mut team = None;
+    let mut team = config.team;
     while team.is_none() {
         println!("Team(A/B): ");
         let input: String = read!("{}\n");
-        if input == "A" {
-            team = Some(Team::A);
-        } else if input == "B" {
-            team = Some(Team::B);
-        }
+        team = config::parse_team(&input).ok();
     }
     let team = team.unwrap();
-    let post_url = match team {
-        Team::A => {
-            POST_URL_A
-        }
-        Team::B => {
-            POST_URL_B
-        }
-    };
-    println!("Map: ");
-    let mut map = None;
+    let post_url = config.post_url(team);
+    let mut map = config.map.as_deref().map(config::map_path);
     while map.is_none() {
         println!("Map(A/B/C): ");
         let input: String = read!("{}\n");
-        if input == "A" {
-            map = Some("./maps/MapA.json");
-        } else if input == "B" {
-            map = Some("./maps/MapB.json");
-        } else if input == "C" {
-            map = Some("./maps/MapC.json");
+        if input == "A" || input == "B" || input == "C" {
+            map = Some(config::map_path(&input));
         }
     };
+    let tick = config.tick_rate.map(|rate| Duration::from_secs_f32(1f32 / rate));
 
-    let client = reqwest::blocking::Client::new();
-    let environment = Arc::new(Mutex::new(Env::new(10)));
-    let map = Arc::new(Map::map_from_file(map.unwrap()));
-    let mut controller = Controller::new(environment.clone(), map.clone(), team);
+    let map_path = map.unwrap();
+    let mut recorder = match &config.record {
+        Some(path) => Some(Recorder::create(path, team, &map_path, rules)?),
+        None => None
+    };
+    let mut client = reqwest::blocking::Client::new();
+    let environment = Arc::new(Mutex::new(Env::with_rules(10, rules.unwrap_or_default(), rules.is_none())));
+    let map = Arc::new(Map::map_from_file(&map_path).with_rules(rules.unwrap_or_default()));
+    let strategy = strategy::by_name(&config.controller, map.clone()).unwrap();
+    let mut controller = Controller::with_strategy(environment.clone(), strategy, team);
+    let data_path = config.data_dir
+        .as_deref()
+        .map(|dir| predictor::data_path(dir, &config.opponent, &map_path));
+    if let Some(path) = &data_path {
+        if let Err(err) = controller.load(path) {
+            eprintln!("could not load {}: {}", path.display(), err);
+        }
+    }
 
     let time = Instant::now();
     println!("map alloc time: {:?}", time.elapsed());
 
 
+    let start = Instant::now();
+    let mut backoff = MIN_BACKOFF;
+    let mut connected = false;
+    let mut last_save = Instant::now();
     loop {
-        let mut response = client.get(GET_URL).send()?;
-        let game_data = response.json::<GameData>()?;
-
-        let (bot, opp) = match team {
-            Team::A => {
-                (&game_data.tankA, &game_data.tankB)
+        let tick_start = Instant::now();
+        match exchange(&client, &config.get_url, &post_url, &mut controller, recorder.as_mut(), start) {
+            Ok(()) => {
+                backoff = MIN_BACKOFF;
+                connected = true;
+                if last_save.elapsed() >= SAVE_INTERVAL {
+                    save(&controller, data_path.as_deref());
+                    last_save = Instant::now();
+                }
+            }
+            Err(err @ Error::Transport(_)) => {
+                //The server going away is the only sign that the match is over
+                if connected {
+                    save(&controller, data_path.as_deref());
+                    last_save = Instant::now();
+                    connected = false;
+                }
+                eprintln!("{}, reconnecting in {:?}", err, backoff);
+                std::thread::sleep(backoff);
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+                client = reqwest::blocking::Client::new();
+                continue;
+            }
+            Err(err) => {
+                eprintln!("skipping frame: {}", err);
             }
-            Team::B => {
-                (&game_data.tankB, &game_data.tankA)
+        }
+        if let Some(tick) = tick {
+            if let Some(rest) = tick.checked_sub(tick_start.elapsed()) {
+                std::thread::sleep(rest);
             }
-        };
-        environment.lock().unwrap().update(
-            &bot,
-            &opp,
-            game_data.projectiles,
-        );
-        let out_data = controller.out_data();
-        controller.update();
-        client.post(post_url).json(
-            &out_data
-        ).send()?;
+        }
     }
-}
\ No newline at end of file
+}
+
+fn save(controller: &Controller, path: Option<&std::path::Path>) {
+    if let Some(path) = path {
+        if let Err(err) = controller.save(path) {
+            eprintln!("could not save {}: {}", path.display(), err);
+        }
+    }
+}
+
+//Polls one frame, updates the controller and posts its action
+fn exchange(client: &reqwest::blocking::Client,
+            get_url: &str,
+            post_url: &str,
+            controller: &mut Controller,
+            mut recorder: Option<&mut Recorder>,
+            start: Instant) -> Result<(), Error> {
+    let response = client.get(get_url).send()?.error_for_status()?;
+    let game_data = response.json::<GameData>()?;
+    let t = start.elapsed().as_secs_f32();
+    if let Some(recorder) = recorder.as_mut() {
+        if let Err(err) = recorder.frame(t, &game_data) {
+            eprintln!("could not record frame: {}", err);
+        }
+    }
+
+    let out_data = controller.handle(game_data, t)?;
+    if let Some(recorder) = recorder.as_mut() {
+        if let Err(err) = recorder.action(t, &out_data) {
+            eprintln!("could not record action: {}", err);
+        }
+    }
+    client.post(post_url).json(
+        &out_data
+    ).send()?.error_for_status()?;
+    Ok(())
+}
+
+//rules replace the ones in the recording when given
+fn run_replay(path: &str, config: &Config, rules: Option<GameRules>) -> Result<(), Box<dyn std::error::Error>> {
+    let records = replay::read(path)?;
+    let (team, map, recorded) = replay::start(&records)?;
+    let mut recorder = match &config.record {
+        Some(out) => Some(Recorder::create(out, team, map, rules.or(recorded))?),
+        None => None
+    };
+    let data_path = config.data_dir
+        .as_deref()
+        .map(|dir| predictor::data_path(dir, &config.opponent, map));
+    let mismatches = replay::replay(&records, &config.controller, rules, data_path.as_deref(), recorder.as_mut())?;
+    for mismatch in &mismatches {
+        println!("frame {} at {:.3}s: recorded {:?}, replayed {:?}",
+                 mismatch.frame, mismatch.t, mismatch.recorded, mismatch.replayed);
+    }
+    let actions = records.iter().filter(|record| matches!(record, Record::Action { .. })).count();
+    println!("{} of {} actions differ", mismatches.len(), actions);
+    Ok(())
+}
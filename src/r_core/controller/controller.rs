@@ -3,20 +3,29 @@ use std::sync::{Arc, Mutex};
 use crate::r_core::state::{Bullet, State, BotData};
 use serde::{Serialize, Deserialize};
 use std::collections::VecDeque;
+use std::io;
+use std::path::Path;
 use std::time::Instant;
 use crate::r_core::math::vec2::Vec2;
 use crate::r_core::map::map::Map;
+use crate::r_core::rules::GameRules;
 use std::ops::Index;
 use std::borrow::Borrow;
-use crate::r_core::map::map::Shape::RotRect;
+use crate::r_core::error::Error;
+use crate::r_core::controller::strategy::{Strategy, Action};
+use crate::r_core::protocol::GameData;
+use crate::r_core::predictor::predictor::Predictor;
+use crate::r_core::controller::dodge::{DodgePlanner, Threat};
+use crate::r_core::controller::targeting::Targeting;
+use crate::r_core::kinematics::{Kinematics, wrap};
 
 const PI: f32 = std::f32::consts::PI;
 
-#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct OutputAction {
-    m: i32,
-    r: i32,
-    f: i32,
+    pub m: i32,
+    pub r: i32,
+    pub f: i32,
 }
 
 impl OutputAction {
@@ -32,18 +41,20 @@ impl OutputAction {
 pub struct Controller {
     env: Arc<Mutex<Env>>,
     out_action: Arc<Mutex<OutputAction>>,
-    map: Arc<Map>,
-    controller: MinimumDangerController,
+    controller: Box<dyn Strategy>,
     team: Team,
 }
 
 impl Controller {
     pub fn new(env: Arc<Mutex<Env>>, map: Arc<Map>, team: Team) -> Self {
+        Self::with_strategy(env, Box::new(MinimumDangerController::new(map)), team)
+    }
+
+    pub fn with_strategy(env: Arc<Mutex<Env>>, strategy: Box<dyn Strategy>, team: Team) -> Self {
         Self {
             env,
-            map: map.clone(),
             out_action: Arc::new(Mutex::new(OutputAction::new())),
-            controller: MinimumDangerController::new(map),
+            controller: strategy,
             team,
         }
     }
@@ -52,6 +63,40 @@ impl Controller {
         *self.out_action.lock().unwrap()
     }
 
+    pub fn team(&self) -> Team {
+        self.team
+    }
+
+    pub fn load(&mut self, path: &Path) -> io::Result<()> {
+        self.controller.load(path)
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        self.controller.save(path)
+    }
+
+    //Feeds a frame from the server through the environment and returns the action to send back,
+    //which is the one decided on the previous frame
+    pub fn handle(&mut self, game_data: GameData, time: f32) -> Result<OutputAction, Error> {
+        let (bot, opp) = match self.team {
+            Team::A => {
+                (&game_data.tank_a, &game_data.tank_b)
+            }
+            Team::B => {
+                (&game_data.tank_b, &game_data.tank_a)
+            }
+        };
+        self.env.lock().unwrap().update(
+            time,
+            bot,
+            opp,
+            game_data.projectiles,
+        )?;
+        let out_data = self.out_data();
+        self.update();
+        Ok(out_data)
+    }
+
     pub fn update(&mut self) {
         let env = &mut *self.env.lock().unwrap();
         let state = env.current_state();
@@ -59,29 +104,39 @@ impl Controller {
             return;
         }
         let state = state.unwrap();
-        let bullet_0 = env.get_bot_bullet(self.team);
-        let bullet_1 = env.get_enemy_bullet(self.team);
+        let bot_bullets = env.friendly_bullets(self.team).map(|projectile| projectile.bullet).collect::<Vec<_>>();
+        let enemy_bullets = env.hostile_bullets(self.team).map(|projectile| projectile.bullet).collect::<Vec<_>>();
+        self.controller.set_rules(env.rules());
         let action = self.controller.action(
             env.last_update().elapsed().as_secs_f32(),
             state,
-            bullet_0,
-            bullet_1,
+            &bot_bullets,
+            &enemy_bullets,
         );
         let out_action = &mut *self.out_action.lock().unwrap();
-        out_action.m = action.0 as i32;
-        out_action.r = -action.1 as i32;
-        out_action.f = if action.2 { 1 } else { 0 };
+        out_action.m = action.move_dir as i32;
+        out_action.r = -action.turn as i32;
+        out_action.f = if action.fire { 1 } else { 0 };
     }
 }
 
 const ANGLE_CNT: usize = 45;
 const PROJECT_DIST: f32 = 200f32;
+//Share of the opponent's likely positions the current heading has to hit before firing
+const FIRE_CHANCE: f32 = 0.6f32;
+//Hostile bullets further away than this are not worth running from
+const DANGER_DIST: f32 = 300f32;
 
 pub struct MinimumDangerController {
     angles: [Vec2; ANGLE_CNT],
     points: [Vec2; ANGLE_CNT],
     max_size: usize,
     map: Arc<Map>,
+    predictor: Predictor,
+    planner: DodgePlanner,
+    targeting: Targeting,
+    kinematics: Kinematics,
+    last_action: Action,
 
     counter: Instant,
 }
@@ -93,10 +148,15 @@ impl MinimumDangerController {
             *angle = Vec2::from_angle(Self::angle(index)) * PROJECT_DIST;
         }
         Self {
+            planner: DodgePlanner::new(map.clone()),
+            targeting: Targeting::new(map.clone()),
+            kinematics: Kinematics::with_rules(map.rules()),
+            last_action: Action::default(),
             map,
             angles,
             points: [Vec2::new(0f32, 0f32); ANGLE_CNT],
             max_size: 10000,
+            predictor: Predictor::new(10000),
             counter: Instant::now(),
         }
     }
@@ -105,101 +165,54 @@ impl MinimumDangerController {
         (index as f32 / ANGLE_CNT as f32 * PI * 2f32)
     }
 
-    pub fn relative_angle(mut angle: f32) -> f32 {
-        angle %= PI * 2f32;
-        if angle >= 0f32 {
-            if angle < PI {
-                angle
-            } else {
-                angle - PI * 2f32
-            }
-        } else {
-            if angle >= -PI {
-                angle
-            } else {
-                angle + PI * 2f32
-            }
-        }
-    }
-
-    pub fn bullet_check(&self, angle: f32, state: State) -> bool {
-        let bot = RotRect {
-            pos: state.bot.pos,
-            w_h: Vec2::new(79.6129 * 1.2f32, 124.67 * 1.2f32),
-            rot: state.bot.r,
-        };
-
-        let enemy = RotRect {
-            pos: state.opp.pos,
-            w_h: Vec2::new(79.6129, 124.67),
-            rot: state.opp.r,
-        };
-        let cos_sin = Vec2::from_angle(angle);
-        let trajectory = self.map.get_bullet_trajectory(
-            Bullet::new(
-                state.bot.pos.x(),
-                state.bot.pos.y(),
-                cos_sin.x() * 400f32,
-                cos_sin.y() * 400f32,
-            ),
-            1f32,
-            100,
-        );
-        let mut start = false;
-        for i in 0..602 {
-            let time = i as f32 / 100f32;
-            let pos = trajectory.position_in(time);
-            if let Some(pos) = pos {
-                if !bot.intersects(pos) {
-                    start = true;
-                } else if start {
-                    return false;
-                }
-                if enemy.intersects(pos) {
-                    return true;
-                }
-            } else {
-                return false;
-            }
-        }
-        return false;
+    pub fn relative_angle(angle: f32) -> f32 {
+        wrap(angle)
     }
+}
 
-    pub fn action(&mut self, delta_time: f32, state: State, bullet_0: Option<Bullet>, bullet_1: Option<Bullet>) -> (f32, f32, bool) {
+impl Strategy for MinimumDangerController {
+    fn action(&mut self, delta_time: f32, state: State, bot_bullets: &[Bullet], enemy_bullets: &[Bullet]) -> Action {
         let mut lowest_danger = f32::INFINITY;
         let mut target = 0;
 
+        self.kinematics.observe(&state, self.last_action);
 
+        //Aim where the opponent will be when the bullet arrives
+        self.predictor.record(&state, &self.map);
+        let mut aim = state;
+        aim.opp.pos = self.predictor.predict(&state, &self.map, &self.kinematics);
+        aim.dist = (aim.opp.pos - aim.bot.pos).sq_magnitude().sqrt();
+        aim.abs_bearing = (aim.opp.pos - aim.bot.pos).angle();
+
+
+        //Hostile bullets about to pass close by, our own are left to the dodge planner
         let mut check_bullet = vec![];
-        if let Some(b_0) = bullet_0 {
-            let trajectory = self.map.get_bullet_trajectory(
-                b_0,
-                1f32,
-                100,
-            );
-            for i in 0..10 {
-                let time = i as f32 * 0.1;
-                if let Some(pos) = trajectory.position_in(time) {
-                    check_bullet.push(pos)
-                }
-            }
-        }
-        if let Some(b_1) = bullet_1 {
+        for &bullet in enemy_bullets {
             let trajectory = self.map.get_bullet_trajectory(
-                b_1,
-                1f32,
-                100,
+                bullet,
+                self.map.rules().max_bounces,
             );
             for i in 0..10 {
                 let time = i as f32 * 0.1;
                 if let Some(pos) = trajectory.position_in(time) {
-                    check_bullet.push(pos)
+                    if (pos - state.bot.pos).sq_magnitude() < DANGER_DIST * DANGER_DIST {
+                        check_bullet.push(pos)
+                    }
                 }
             }
         }
 
+        //The opponent moves from where it is now to the prediction while a bullet is in the air
+        let flight_time = (state.dist / self.map.rules().bullet_speed).max(1e-3f32);
+        let lead = aim.opp.pos - state.opp.pos;
+        let opp_at = |time: f32| state.opp.pos + lead * (time / flight_time);
+        let solution = if check_bullet.is_empty() {
+            self.targeting.aim(&state.bot, state.opp.r, &opp_at)
+        } else {
+            None
+        };
+
         let mut dodge = false;
-        let mut shoot = false;
         for (index, p_pos) in self.angles.iter().enumerate() {
             let pos = state.bot.pos + *p_pos;
             let mut danger = 0f32;
@@ -216,12 +229,13 @@ impl MinimumDangerController {
                 danger += 1f32 / (1f32 + dist);
                 let dist = (pos - state.opp.pos).sq_magnitude().sqrt();
                 danger -= 100f32 / (1f32 + (dist.powi(2)));
-                if !dodge && self.bullet_check(Self::angle(index), state) {
-                    danger -= 1f32 / (1f32 + (Self::angle(index) - state.abs_bearing).abs());
-                    shoot = true;
+                if let Some(solution) = solution {
+                    if Self::relative_angle(Self::angle(index) - solution.angle).abs() < PI / ANGLE_CNT as f32 {
+                        danger -= solution.hit_chance / (1f32 + (Self::angle(index) - aim.abs_bearing).abs());
+                    }
                 }
 
-                let diff = Self::angle(index) - state.abs_bearing;
+                let diff = Self::angle(index) - aim.abs_bearing;
                 danger -= 100f32 / (1f32 + Self::relative_angle(diff).powi(2));
                 if danger < lowest_danger {
                     lowest_danger = danger;
@@ -242,12 +256,131 @@ impl MinimumDangerController {
                 turn_amt += std::f32::consts::PI;
             }
         }
-        let final_shoot = turn_amt.abs() < (100f32 / state.dist) && shoot && state.bot.can_fire;
-        let turn_amt = if turn_amt.abs() < (100f32 / state.dist) || final_shoot {
+        //Line up with the best shot, but fire whenever the current heading is good enough
+        if let Some(solution) = solution {
+            turn_amt = Self::relative_angle(-solution.angle - state.bot.r);
+        }
+        let final_shoot = !dodge && state.bot.can_fire
+            && self.targeting.evaluate(&state.bot, state.opp.r, &opp_at, -state.bot.r).hit_chance >= FIRE_CHANCE;
+        let turn_amt = if turn_amt.abs() < (100f32 / aim.dist) || final_shoot {
             0f32
         } else {
             turn_amt.signum()
         };
-        (move_dir, turn_amt, final_shoot)
+        let action = Action {
+            move_dir,
+            turn: turn_amt,
+            fire: final_shoot,
+        };
+
+        //Only steer away from the plan above when it walks into a bullet
+        let threats = bot_bullets
+            .iter()
+            .map(|&bullet| Threat { bullet, own: true })
+            .chain(enemy_bullets.iter().map(|&bullet| Threat { bullet, own: false }))
+            .collect::<Vec<_>>();
+        let plan = self.planner.plan(&state.bot, &self.kinematics, &threats, action);
+        //Hold fire if the shot would come back into us on the way we are going
+        let fire = final_shoot && self.planner.self_hit(&state.bot, &self.kinematics, &plan, -state.bot.r).is_none();
+        self.last_action = Action {
+            move_dir: plan.first.move_dir,
+            turn: plan.first.turn,
+            fire,
+        };
+        self.last_action
+    }
+
+    fn load(&mut self, path: &Path) -> io::Result<()> {
+        self.predictor.load(path)
+    }
+
+    fn save(&self, path: &Path) -> io::Result<()> {
+        self.predictor.save(path)
+    }
+
+    fn set_rules(&mut self, rules: GameRules) {
+        let old = *self.map.rules();
+        if rules != old {
+            self.map = Arc::new(self.map.with_rules(rules));
+            self.planner = DodgePlanner::new(self.map.clone());
+            self.targeting = Targeting::new(self.map.clone());
+            //What has been learned about our tank only goes when the rules for it change
+            if (old.tank_speed, old.turn_rate) != (rules.tank_speed, rules.turn_rate) {
+                self.kinematics = Kinematics::with_rules(&rules);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::r_core::state::TankMotion;
+
+    fn state(x: f32, time: f32) -> State {
+        let bot = BotData {
+            pos: Vec2::new(x, 360f32),
+            r: 0f32,
+            can_fire: true,
+        };
+        let opp = BotData {
+            pos: Vec2::new(1000f32, 360f32),
+            r: 0f32,
+            can_fire: true,
+        };
+        State {
+            bot,
+            opp,
+            dist: 1000f32 - x,
+            abs_bearing: 0f32,
+            time,
+            bot_motion: TankMotion::still(time),
+            opp_motion: TankMotion::still(time),
+        }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn new_rules_keep_what_was_learned_about_our_tank() {
+        let mut controller = MinimumDangerController::new(Arc::new(Map::new(vec![])));
+        let forward = Action {
+            move_dir: 1f32,
+            turn: 0f32,
+            fire: false,
+        };
+        //Driving at 100px/s, slower than the rules say
+        for tick in 0..10 {
+            let time = tick as f32 * 0.1f32;
+            controller.kinematics.observe(&state(200f32 + time * 100f32, time), forward);
+        }
+        let learned = controller.kinematics.speed();
+        assert!(learned < GameRules::default().tank_speed);
+        controller.set_rules(GameRules {
+            bullet_speed: 500f32,
+            ..GameRules::default()
+        });
+        assert_eq!(controller.map.rules().bullet_speed, 500f32);
+        assert_eq!(controller.kinematics.speed(), learned);
+        let rules = GameRules {
+            bullet_speed: 500f32,
+            tank_speed: 200f32,
+            ..GameRules::default()
+        };
+        controller.set_rules(rules);
+        assert_eq!(controller.kinematics.speed(), rules.tank_speed);
+    }
+
+    #[test]
+    fn keeps_aiming_while_its_own_bullet_flies() {
+        let mut controller = MinimumDangerController::new(Arc::new(Map::new(vec![])));
+        let mut state = state(200f32, 0f32);
+        state.bot.can_fire = false;
+        let own = Bullet::new(400f32, 360f32, 400f32, 0f32);
+        controller.action(0f32, state, &[own], &[]);
+        assert!(controller.targeting.last.is_some());
+        //A hostile bullet far away does not stop it either
+        let mut controller = MinimumDangerController::new(Arc::new(Map::new(vec![])));
+        let far = Bullet::new(1200f32, 100f32, 400f32, 0f32);
+        controller.action(0f32, state, &[], &[far]);
+        assert!(controller.targeting.last.is_some());
+    }
+}
@@ -0,0 +1,300 @@
+use crate::r_core::controller::strategy::Action;
+use crate::r_core::map::map::{Map, Shape, BulletTrajectory};
+use crate::r_core::math::vec2::Vec2;
+use crate::r_core::kinematics::{Kinematics, Motion};
+use crate::r_core::state::{BotData, Bullet};
+use std::sync::Arc;
+
+pub const PLAN_TICKS: usize = 30;
+pub const PLAN_DT: f32 = 0.05f32;
+//The reported velocity is rounded, so every bullet is also tried slightly turned either way
+const SPREAD: [f32; 5] = [-0.02f32, -0.01f32, 0f32, 0.01f32, 0.02f32];
+const HULL_MARGIN: f32 = 1.1f32;
+
+#[derive(Debug, Copy, Clone)]
+pub struct Threat {
+    pub bullet: Bullet,
+    //Our own bullet only counts once it has left our hull
+    pub own: bool,
+}
+
+//Two commands, each held for half of the planned ticks
+#[derive(Debug, Copy, Clone)]
+pub struct Plan {
+    pub first: Action,
+    pub second: Action,
+    pub hit_chance: f32,
+    pub first_hit: f32,
+}
+
+struct Sample {
+    trajectory: BulletTrajectory,
+    own: bool,
+}
+
+pub struct DodgePlanner {
+    map: Arc<Map>,
+}
+
+impl DodgePlanner {
+    pub fn new(map: Arc<Map>) -> Self {
+        Self {
+            map,
+        }
+    }
+
+    fn commands() -> Vec<Action> {
+        let mut commands = vec![];
+        for &move_dir in &[1f32, -1f32, 0f32] {
+            for &turn in &[0f32, 1f32, -1f32] {
+                commands.push(Action {
+                    move_dir,
+                    turn,
+                    fire: false,
+                });
+            }
+        }
+        commands
+    }
+
+    fn hull(&self, pos: Vec2, rot: f32) -> Shape {
+        Shape::RotRect {
+            pos,
+            w_h: self.map.rules().hull_extents() * HULL_MARGIN,
+            rot,
+        }
+    }
+
+    fn samples(&self, threats: &[Threat]) -> Vec<Sample> {
+        let mut samples = vec![];
+        for threat in threats {
+            for &angle in &SPREAD {
+                let mut vel = threat.bullet.vel();
+                vel.rotate(Vec2::from_angle(angle));
+                samples.push(Sample {
+                    trajectory: self.map.get_bullet_trajectory(Bullet::new_v(threat.bullet.pos(), vel), self.map.rules().max_bounces),
+                    own: threat.own,
+                });
+            }
+        }
+        samples
+    }
+
+    //Moves the tank like the server does, a blocked move or turn leaves it where it was
+    fn drive(&self, kinematics: &Kinematics, motion: Motion, action: Action, dt: f32) -> Motion {
+        let mut next = kinematics.advance(motion, action, dt);
+        if self.map.blocks_tank(motion.pos, next.rot) {
+            next.rot = motion.rot;
+            next.turn = 0f32;
+        }
+        if self.map.blocks_tank(next.pos, next.rot) {
+            next.pos = motion.pos;
+            next.speed = 0f32;
+        }
+        next
+    }
+
+    //Where the tank is after every tick of holding first and then second
+    fn motions(&self, bot: &BotData, kinematics: &Kinematics, first: Action, second: Action) -> Vec<Motion> {
+        let mut motion = Motion {
+            pos: bot.pos,
+            rot: bot.r,
+            speed: kinematics.motion().map_or(0f32, |motion| motion.speed),
+            turn: kinematics.motion().map_or(0f32, |motion| motion.turn),
+        };
+        let mut motions = Vec::with_capacity(PLAN_TICKS);
+        for tick in 0..PLAN_TICKS {
+            let action = if tick < PLAN_TICKS / 2 { first } else { second };
+            motion = self.drive(kinematics, motion, action, PLAN_DT);
+            motions.push(motion);
+        }
+        motions
+    }
+
+    //The hull during the tick a time falls in, the last one after the plan ends
+    fn hull_at(&self, motions: &[Motion], time: f32) -> Shape {
+        let tick = ((time / PLAN_DT).ceil() as usize).max(1) - 1;
+        let motion = motions[tick.min(motions.len() - 1)];
+        self.hull(motion.pos, motion.rot)
+    }
+
+    //Share of the bullet samples that hit the tank and when the first one does
+    fn evaluate(&self, bot: &BotData, kinematics: &Kinematics, samples: &[Sample], first: Action, second: Action) -> (f32, f32) {
+        let motions = self.motions(bot, kinematics, first, second);
+        let hull = |time: f32| self.hull_at(&motions, time);
+        let horizon = PLAN_TICKS as f32 * PLAN_DT;
+        let mut hits = 0;
+        let mut first_hit = f32::INFINITY;
+        for sample in samples {
+            let hit = if sample.own {
+                sample.trajectory.returns_to(&hull, horizon)
+            } else {
+                sample.trajectory.strikes(&hull, horizon)
+            };
+            if let Some(time) = hit {
+                hits += 1;
+                first_hit = first_hit.min(time);
+            }
+        }
+        (hits as f32 / samples.len().max(1) as f32, first_hit)
+    }
+
+    //When a bullet fired along angle now would come back into us while following the plan
+    pub fn self_hit(&self, bot: &BotData, kinematics: &Kinematics, plan: &Plan, angle: f32) -> Option<f32> {
+        let motions = self.motions(bot, kinematics, plan.first, plan.second);
+        self.map.self_hit(bot.pos, angle, self.map.rules().max_bounces, |time| self.hull_at(&motions, time), PLAN_TICKS as f32 * PLAN_DT)
+    }
+
+    //Chance of being hit while holding one command
+    pub fn hit_chance(&self, bot: &BotData, kinematics: &Kinematics, threats: &[Threat], action: Action) -> f32 {
+        let samples = self.samples(threats);
+        self.evaluate(bot, kinematics, &samples, action, action).0
+    }
+
+    //The command sequence least likely to get hit, preferred wins every tie
+    pub fn plan(&self, bot: &BotData, kinematics: &Kinematics, threats: &[Threat], preferred: Action) -> Plan {
+        let samples = self.samples(threats);
+        let (hit_chance, first_hit) = self.evaluate(bot, kinematics, &samples, preferred, preferred);
+        let mut best = Plan {
+            first: preferred,
+            second: preferred,
+            hit_chance,
+            first_hit,
+        };
+        if samples.is_empty() || hit_chance == 0f32 {
+            return best;
+        }
+        let commands = Self::commands();
+        for &first in &commands {
+            for &second in &commands {
+                let (hit_chance, first_hit) = self.evaluate(bot, kinematics, &samples, first, second);
+                if hit_chance < best.hit_chance || (hit_chance == best.hit_chance && first_hit > best.first_hit) {
+                    best = Plan {
+                        first,
+                        second,
+                        hit_chance,
+                        first_hit,
+                    };
+                }
+            }
+        }
+        best
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bot(pos: Vec2, r: f32) -> BotData {
+        BotData {
+            pos,
+            r,
+            can_fire: true,
+        }
+    }
+
+    #[test]
+    fn steps_out_of_a_bullets_way() {
+        let planner = DodgePlanner::new(Arc::new(Map::new(vec![])));
+        let bot = bot(Vec2::new(640f32, 360f32), 0f32);
+        //Coming straight down at the side of the tank, which faces along x
+        let threats = [Threat {
+            bullet: Bullet::new(640f32, -300f32, 0f32, 400f32),
+            own: false,
+        }];
+        let still = Action::default();
+        assert_eq!(planner.hit_chance(&bot, &Kinematics::new(), &threats, still), 1f32);
+        let plan = planner.plan(&bot, &Kinematics::new(), &threats, still);
+        assert_eq!(plan.hit_chance, 0f32);
+        assert!(plan.first.move_dir != 0f32);
+    }
+
+    #[test]
+    fn bullets_keep_their_reported_speed() {
+        let planner = DodgePlanner::new(Arc::new(Map::new(vec![])));
+        let bot = bot(Vec2::new(640f32, 360f32), 0f32);
+        //Out of reach within the plan at the rules' speed, not at three times that
+        let fast = [Threat {
+            bullet: Bullet::new(640f32, -600f32, 0f32, 1200f32),
+            own: false,
+        }];
+        let still = Action::default();
+        assert_eq!(planner.hit_chance(&bot, &Kinematics::new(), &fast, still), 1f32);
+        let (_, first_hit) = planner.evaluate(&bot, &Kinematics::new(), &planner.samples(&fast), still, still);
+        let reach = 960f32 - planner.map.rules().hull_extents().y() * HULL_MARGIN;
+        assert!((first_hit - reach / 1200f32).abs() <= 0.02f32, "{}", first_hit);
+        let slow = [Threat {
+            bullet: Bullet::new(640f32, -600f32, 0f32, 400f32),
+            own: false,
+        }];
+        assert_eq!(planner.hit_chance(&bot, &Kinematics::new(), &slow, still), 0f32);
+    }
+
+    #[test]
+    fn keeps_the_preferred_command_when_safe() {
+        let planner = DodgePlanner::new(Arc::new(Map::new(vec![])));
+        let bot = bot(Vec2::new(640f32, 360f32), 0f32);
+        let threats = [Threat {
+            bullet: Bullet::new(100f32, 100f32, 400f32, 0f32),
+            own: false,
+        }];
+        let preferred = Action {
+            move_dir: 1f32,
+            turn: -1f32,
+            fire: false,
+        };
+        let plan = planner.plan(&bot, &Kinematics::new(), &threats, preferred);
+        assert_eq!(plan.hit_chance, 0f32);
+        assert_eq!(plan.first, preferred);
+        assert_eq!(plan.second, preferred);
+    }
+
+    #[test]
+    fn own_bullet_counts_after_leaving_the_hull() {
+        let planner = DodgePlanner::new(Arc::new(Map::new(vec![])));
+        let bot = bot(Vec2::new(640f32, 360f32), 0f32);
+        let leaving = [Threat {
+            bullet: Bullet::new(640f32, 360f32, 400f32, 0f32),
+            own: true,
+        }];
+        assert_eq!(planner.hit_chance(&bot, &Kinematics::new(), &leaving, Action::default()), 0f32);
+        let returning = [Threat {
+            bullet: Bullet::new(900f32, 360f32, -400f32, 0f32),
+            own: true,
+        }];
+        assert_eq!(planner.hit_chance(&bot, &Kinematics::new(), &returning, Action::default()), 1f32);
+    }
+
+    #[test]
+    fn sees_its_own_shot_coming_back() {
+        let wall = Shape::Rect {
+            pos: Vec2::new(900f32, 360f32),
+            w_h: Vec2::new(20f32, 300f32),
+        };
+        let planner = DodgePlanner::new(Arc::new(Map::new(vec![wall])));
+        let facing = bot(Vec2::new(640f32, 360f32), 0f32);
+        let still = Action::default();
+        let plan = Plan {
+            first: still,
+            second: still,
+            hit_chance: 0f32,
+            first_hit: f32::INFINITY,
+        };
+        assert!(planner.self_hit(&facing, &Kinematics::new(), &plan, 0f32).is_some());
+        assert!(planner.self_hit(&facing, &Kinematics::new(), &plan, std::f32::consts::PI).is_none());
+        //Driving sideways out of the way in time
+        let away = Action {
+            move_dir: 1f32,
+            turn: 0f32,
+            fire: false,
+        };
+        let sideways = bot(Vec2::new(640f32, 360f32), -std::f32::consts::FRAC_PI_2);
+        let plan = Plan {
+            first: away,
+            second: away,
+            ..plan
+        };
+        assert!(planner.self_hit(&sideways, &Kinematics::new(), &plan, 0f32).is_none());
+    }
+}
@@ -1 +1,4 @@
-pub mod controller;
\ No newline at end of file
+pub mod controller;
+pub mod strategy;
+pub mod dodge;
+pub mod targeting;
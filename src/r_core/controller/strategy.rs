@@ -0,0 +1,63 @@
+use crate::r_core::controller::controller::MinimumDangerController;
+use crate::r_core::map::map::Map;
+use crate::r_core::rules::GameRules;
+use crate::r_core::state::{Bullet, State};
+use std::sync::Arc;
+use std::io;
+use std::path::Path;
+
+//What a strategy wants the tank to do this tick.
+//move_dir and turn are in [-1, 1], a positive turn increases the heading.
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub struct Action {
+    pub move_dir: f32,
+    pub turn: f32,
+    pub fire: bool,
+}
+
+pub trait Strategy {
+    //Every bullet in flight, ours and the ones that can hit us
+    fn action(&mut self, delta_time: f32, state: State, bot_bullets: &[Bullet], enemy_bullets: &[Bullet]) -> Action;
+
+    //Strategies that learn about the opponent keep what they learned between matches
+    fn load(&mut self, _path: &Path) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn save(&self, _path: &Path) -> io::Result<()> {
+        Ok(())
+    }
+
+    //Called before every action with the rules as currently known, they can change as
+    //projectiles are observed
+    fn set_rules(&mut self, _rules: GameRules) {}
+}
+
+//Sits still, useful as a baseline opponent in the simulator
+pub struct Idle;
+
+impl Strategy for Idle {
+    fn action(&mut self, _: f32, _: State, _: &[Bullet], _: &[Bullet]) -> Action {
+        Action::default()
+    }
+}
+
+pub type StrategyFactory = fn(Arc<Map>) -> Box<dyn Strategy>;
+
+pub const DEFAULT_STRATEGY: &str = "minimum_danger";
+
+pub const STRATEGIES: [(&str, StrategyFactory); 2] = [
+    (DEFAULT_STRATEGY, |map| Box::new(MinimumDangerController::new(map))),
+    ("idle", |_| Box::new(Idle)),
+];
+
+pub fn strategy_names() -> impl Iterator<Item=&'static str> {
+    STRATEGIES.iter().map(|(name, _)| *name)
+}
+
+pub fn by_name(name: &str, map: Arc<Map>) -> Option<Box<dyn Strategy>> {
+    STRATEGIES
+        .iter()
+        .find(|(strategy, _)| *strategy == name)
+        .map(|(_, factory)| factory(map))
+}
@@ -0,0 +1,298 @@
+use crate::r_core::map::map::{Map, Shape};
+use crate::r_core::math::vec2::Vec2;
+use crate::r_core::state::{BotData, Bullet};
+use crate::r_core::kinematics::wrap;
+use std::sync::Arc;
+
+const PI: f32 = std::f32::consts::PI;
+
+pub const FIRE_ANGLES: usize = 120;
+//Time between checked bullet positions, a bullet moves 8px in it
+const STEP: f32 = 0.02f32;
+//Longer shots give the opponent too much time to get out of the way
+const MAX_FLIGHT: f32 = 3f32;
+const MAX_SHOT_BOUNCES: usize = 3;
+//Bounces allowed for the exact bank shots, more leave too little room for error
+const BANK_BOUNCES: usize = 2;
+//The opponent is also tried this far ahead of and behind the prediction along its heading,
+//the only way it can get out of the way
+const OFFSETS: [f32; 5] = [-40f32, -20f32, 0f32, 20f32, 40f32];
+//A solution is reused until either tank or the opponent's predicted path moves further than
+//this, or either tank turns more than RESOLVE_TURN
+const RESOLVE_DIST: f32 = 8f32;
+const RESOLVE_TURN: f32 = 0.05f32;
+
+#[derive(Debug, Copy, Clone)]
+pub struct Solution {
+    pub angle: f32,
+    pub hit_chance: f32,
+    //Flight time and bounces until the first hit, infinite and 0 for a miss
+    pub time: f32,
+    pub bounces: usize,
+}
+
+//What the last solution was solved for
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct Aim {
+    shooter: Vec2,
+    //Our hull's heading matters for shots that come back
+    shooter_rot: f32,
+    target_rot: f32,
+    //Where the opponent is and where it will be a second later
+    target: (Vec2, Vec2),
+    solution: Option<Solution>,
+}
+
+//Looks for fire angles that hit the opponent where it will be, straight or off the walls
+pub struct Targeting {
+    map: Arc<Map>,
+    pub(crate) last: Option<Aim>,
+}
+
+impl Targeting {
+    pub fn new(map: Arc<Map>) -> Self {
+        Self {
+            map,
+            last: None,
+        }
+    }
+
+    fn hull(&self, pos: Vec2, rot: f32) -> Shape {
+        Shape::RotRect {
+            pos,
+            w_h: self.map.rules().hull_extents(),
+            rot,
+        }
+    }
+
+    //Fires along the world angle from the shooter's center. target gives the opponent's center
+    //a given time after firing, it is assumed to keep its heading. A shot that comes back into
+    //the shooter before it hits counts as a miss.
+    pub fn evaluate<F: Fn(f32) -> Vec2>(&self, shooter: &BotData, target_rot: f32, target: &F, angle: f32) -> Solution {
+        let speed = self.map.rules().bullet_speed;
+        let trajectory = self.map.get_bullet_trajectory(
+            Bullet::new_v(shooter.pos, Vec2::from_angle(angle) * speed),
+            MAX_SHOT_BOUNCES,
+        );
+        let samples = trajectory.sample(speed * STEP, (MAX_FLIGHT / STEP) as usize);
+        let own = self.hull(shooter.pos, shooter.r);
+        let returns = trajectory.returns_to(|_| own, MAX_FLIGHT).unwrap_or(f32::INFINITY);
+        let forward = Vec2::from_angle(-target_rot);
+        let mut hit = [false; OFFSETS.len()];
+        let mut first = (f32::INFINITY, 0);
+        for (index, sample) in samples.into_iter().enumerate() {
+            let (pos, bounces) = match sample {
+                Some(sample) => sample,
+                None => break
+            };
+            let time = (index + 1) as f32 * STEP;
+            if time >= returns {
+                break;
+            }
+            let center = target(time);
+            for (offset, hit) in OFFSETS.iter().zip(hit.iter_mut()) {
+                if !*hit && self.hull(center + forward * *offset, target_rot).intersects(pos) {
+                    *hit = true;
+                    if time < first.0 {
+                        first = (time, bounces);
+                    }
+                }
+            }
+            if hit.iter().all(|&hit| hit) {
+                break;
+            }
+        }
+        Solution {
+            angle,
+            hit_chance: hit.iter().filter(|&&hit| hit).count() as f32 / OFFSETS.len() as f32,
+            time: first.0,
+            bounces: first.1,
+        }
+    }
+
+    //The angle most likely to hit, fewer bounces and then a shorter flight break ties.
+    //None when nothing can hit. Unless a sweep of angles finds a sure hit, the exact bank shots to
+    //where the opponent will be after a straight flight are tried too, the sweep misses narrow gaps.
+    pub fn solve<F: Fn(f32) -> Vec2>(&self, shooter: &BotData, target_rot: f32, target: &F) -> Option<Solution> {
+        let sweep = (0..FIRE_ANGLES).map(|index| index as f32 / FIRE_ANGLES as f32 * PI * 2f32);
+        let swept = self.best(shooter, target_rot, target, sweep);
+        if swept.map_or(false, |best| best.hit_chance == 1f32) {
+            return swept;
+        }
+        let direct = (target(0f32) - shooter.pos).sq_magnitude().sqrt() / self.map.rules().bullet_speed;
+        let banks = self.map
+            .bank_shots(shooter.pos, target(direct), BANK_BOUNCES)
+            .into_iter()
+            .map(|shot| shot.angle);
+        self.best(shooter, target_rot, target, swept.map(|best| best.angle).into_iter().chain(banks))
+    }
+
+    //Like solve, but hands back the last solution while little has changed since it was found
+    pub fn aim<F: Fn(f32) -> Vec2>(&mut self, shooter: &BotData, target_rot: f32, target: &F) -> Option<Solution> {
+        let path = (target(0f32), target(1f32));
+        let close = |a: Vec2, b: Vec2| (a - b).sq_magnitude() <= RESOLVE_DIST * RESOLVE_DIST;
+        if let Some(last) = self.last {
+            if close(last.shooter, shooter.pos)
+                && close(last.target.0, path.0)
+                && close(last.target.1, path.1)
+                && wrap(last.shooter_rot - shooter.r).abs() <= RESOLVE_TURN
+                && wrap(last.target_rot - target_rot).abs() <= RESOLVE_TURN {
+                return last.solution;
+            }
+        }
+        let solution = self.solve(shooter, target_rot, target);
+        self.last = Some(Aim {
+            shooter: shooter.pos,
+            shooter_rot: shooter.r,
+            target_rot,
+            target: path,
+            solution,
+        });
+        solution
+    }
+
+    fn best<F: Fn(f32) -> Vec2, I: Iterator<Item=f32>>(&self, shooter: &BotData, target_rot: f32, target: &F, angles: I) -> Option<Solution> {
+        let mut best: Option<Solution> = None;
+        for angle in angles {
+            let solution = self.evaluate(shooter, target_rot, target, angle);
+            if solution.hit_chance == 0f32 {
+                continue;
+            }
+            let better = match best {
+                None => true,
+                Some(best) => solution.hit_chance > best.hit_chance
+                    || (solution.hit_chance == best.hit_chance
+                    && (solution.bounces, solution.time) < (best.bounces, best.time))
+            };
+            if better {
+                best = Some(solution);
+            }
+        }
+        best
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate test;
+
+    use super::*;
+    use test::Bencher;
+
+    fn shooter(pos: Vec2) -> BotData {
+        BotData {
+            pos,
+            r: 0f32,
+            can_fire: true,
+        }
+    }
+
+    #[test]
+    fn leads_a_moving_target() {
+        let targeting = Targeting::new(Arc::new(Map::new(vec![])));
+        let shooter = shooter(Vec2::new(200f32, 360f32));
+        //Driving down the screen at 150px/s, 800px away
+        let target = |time: f32| Vec2::new(1000f32, 200f32 + time * 150f32);
+        let solution = targeting.solve(&shooter, -PI * 0.5f32, &target).unwrap();
+        assert_eq!(solution.hit_chance, 1f32);
+        assert_eq!(solution.bounces, 0);
+        //Aimed ahead of where the target is now
+        assert!(solution.angle > (Vec2::new(1000f32, 200f32) - shooter.pos).angle());
+        //Hits the near side of the hull, short of the 2s it takes to reach the center
+        assert!(solution.time > 1.5f32 && solution.time < 2f32, "{}", solution.time);
+    }
+
+    #[test]
+    fn banks_around_a_wall() {
+        let wall = Shape::Rect {
+            pos: Vec2::new(640f32, 400f32),
+            w_h: Vec2::new(40f32, 320f32),
+        };
+        let ceiling = Shape::Rect {
+            pos: Vec2::new(640f32, -50f32),
+            w_h: Vec2::new(700f32, 50f32),
+        };
+        let targeting = Targeting::new(Arc::new(Map::new(vec![wall, ceiling])));
+        let shooter = shooter(Vec2::new(300f32, 400f32));
+        let target = |_: f32| Vec2::new(980f32, 400f32);
+        let straight = targeting.evaluate(&shooter, 0f32, &target, 0f32);
+        assert_eq!(straight.hit_chance, 0f32);
+        let solution = targeting.solve(&shooter, 0f32, &target).unwrap();
+        assert!(solution.bounces > 0);
+        assert!(solution.hit_chance > 0f32);
+    }
+
+    #[test]
+    fn threads_a_gap_the_sweep_misses() {
+        let shooter = shooter(Vec2::new(200f32, 360f32));
+        //Halfway between two of the swept angles, through a 16px gap halfway there
+        let target = Vec2::new(1000f32, 360f32 + 800f32 * (PI / FIRE_ANGLES as f32).tan());
+        let gap = (shooter.pos.y() + target.y()) * 0.5f32;
+        let walls = vec![Shape::Rect {
+            pos: Vec2::new(600f32, gap - 308f32),
+            w_h: Vec2::new(10f32, 300f32),
+        }, Shape::Rect {
+            pos: Vec2::new(600f32, gap + 308f32),
+            w_h: Vec2::new(10f32, 300f32),
+        }];
+        let targeting = Targeting::new(Arc::new(Map::new(walls)));
+        let at = |_: f32| target;
+        assert!((0..FIRE_ANGLES)
+            .all(|index| targeting.evaluate(&shooter, 0f32, &at, index as f32 / FIRE_ANGLES as f32 * PI * 2f32).hit_chance == 0f32));
+        let solution = targeting.solve(&shooter, 0f32, &at).unwrap();
+        assert_eq!(solution.bounces, 0);
+        assert!((solution.angle - (target - shooter.pos).angle()).abs() < 1e-3f32);
+    }
+
+    #[test]
+    fn a_shot_that_comes_back_misses() {
+        let wall = Shape::Rect {
+            pos: Vec2::new(400f32, 360f32),
+            w_h: Vec2::new(20f32, 200f32),
+        };
+        let targeting = Targeting::new(Arc::new(Map::new(vec![wall])));
+        let shooter = shooter(Vec2::new(200f32, 360f32));
+        //Behind us, reachable only by the bullet bouncing straight back through our own hull
+        let target = |_: f32| Vec2::new(0f32, 360f32);
+        assert_eq!(targeting.evaluate(&shooter, 0f32, &target, 0f32).hit_chance, 0f32);
+    }
+
+    #[test]
+    fn aim_reuses_a_solution_until_something_moves() {
+        let mut targeting = Targeting::new(Arc::new(Map::new(vec![])));
+        let shooter = shooter(Vec2::new(200f32, 360f32));
+        let target = |time: f32| Vec2::new(1000f32, 200f32 + time * 150f32);
+        let first = targeting.aim(&shooter, -PI * 0.5f32, &target).unwrap();
+        //Cached, so a path that would change the answer is not looked at
+        let nudged = |time: f32| target(time) + Vec2::new(0f32, 5f32);
+        assert_eq!(targeting.aim(&shooter, -PI * 0.5f32, &nudged).unwrap().angle, first.angle);
+        let moved = |time: f32| target(time) + Vec2::new(0f32, 100f32);
+        let solution = targeting.aim(&shooter, -PI * 0.5f32, &moved).unwrap();
+        assert_eq!(solution.angle, targeting.solve(&shooter, -PI * 0.5f32, &moved).unwrap().angle);
+        assert!(solution.angle != first.angle);
+        //Turning changes which shots come back into us
+        let turned = BotData {
+            r: 1f32,
+            ..shooter
+        };
+        targeting.aim(&turned, -PI * 0.5f32, &moved);
+        assert_eq!(targeting.last.unwrap().shooter_rot, 1f32);
+    }
+
+    #[bench]
+    fn bench_solve(b: &mut Bencher) {
+        let targeting = Targeting::new(Arc::new(Map::map_from_file("./maps/MapB.json")));
+        let shooter = shooter(Vec2::new(800f32, 320f32));
+        let target = |time: f32| Vec2::new(1200f32, 320f32 + time * 150f32);
+        b.iter(|| targeting.solve(&shooter, -PI * 0.5f32, &target));
+    }
+
+    #[bench]
+    fn bench_solve_banked(b: &mut Bencher) {
+        //No straight shot, so the bank shots are looked for as well
+        let targeting = Targeting::new(Arc::new(Map::map_from_file("./maps/MapB.json")));
+        let shooter = shooter(Vec2::new(1160f32, 440f32));
+        let target = |_: f32| Vec2::new(360f32, 360f32);
+        b.iter(|| targeting.solve(&shooter, 0f32, &target));
+    }
+}
@@ -1,81 +1,459 @@
 use std::collections::{VecDeque, HashMap};
 use crate::r_core::state::{State, BotData, Bullet};
-use crate::{TankData, Projectile};
+use crate::r_core::math::vec2::Vec2;
+use crate::r_core::protocol::{TankData, Projectile};
+use crate::r_core::error::Error;
+use crate::r_core::rules::GameRules;
 use std::time::Instant;
+use serde::{Serialize, Deserialize};
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Team {
     A,
     B,
 }
 
+//Bullets seen before the observed speed replaces the one in the rules
+const MIN_SPEED_SAMPLES: usize = 10;
+//Reported velocities are rounded, so the observed speed has to be off by more than this
+const SPEED_TOLERANCE: f32 = 1f32;
+//A projectile further than this from where its key was last seen plus a frame of flight is
+//a new shot reusing the key
+const JUMP_TOLERANCE: f32 = 30f32;
+
+//A projectile followed across frames
+#[derive(Debug, Clone)]
+pub struct TrackedProjectile {
+    //Stays the same for as long as the projectile is in flight, never reused
+    pub id: u64,
+    pub key: String,
+    //None when the key does not say who fired it
+    pub owner: Option<Team>,
+    pub first_seen: f32,
+    pub last_seen: f32,
+    pub bullet: Bullet,
+    //Every velocity it was seen with and when it was first seen with it, a change is a bounce
+    pub velocities: Vec<(f32, Vec2)>,
+}
+
+impl TrackedProjectile {
+    //Keys are p0 and p1 for the bullets of team A and B, optionally followed by a separator
+    //and anything else when the server allows more than one shot each
+    pub fn owner_from_key(key: &str) -> Option<Team> {
+        let owned = |prefix: &str| key.strip_prefix(prefix)
+            .map_or(false, |rest| rest.chars().next().map_or(true, |next| !next.is_ascii_alphanumeric()));
+        if owned("p0") {
+            Some(Team::A)
+        } else if owned("p1") {
+            Some(Team::B)
+        } else {
+            None
+        }
+    }
+
+    fn observe(&mut self, time: f32, bullet: Bullet) {
+        let vel = bullet.vel();
+        if self.velocities.last().map_or(true, |&(_, last)| last != vel) {
+            self.velocities.push((time, vel));
+        }
+        self.bullet = bullet;
+        self.last_seen = time;
+    }
+}
+
 pub struct Env {
     history: VecDeque<State>,
-    p0_projectile: Option<Bullet>,
-    p1_projectile: Option<Bullet>,
+    projectiles: Vec<TrackedProjectile>,
+    next_id: u64,
     max_size: usize,
     update_time: Instant,
+    rules: GameRules,
+    infer_rules: bool,
+    speed_total: f32,
+    speed_samples: usize,
 }
 
 impl Env {
+    //Starts from the default rules and takes the bullet speed from observed projectiles
     pub fn new(max_size: usize) -> Self {
+        Self::with_rules(max_size, GameRules::default(), true)
+    }
+
+    pub fn with_rules(max_size: usize, rules: GameRules, infer_rules: bool) -> Self {
         Self {
             history: VecDeque::with_capacity(max_size),
-            p0_projectile: None,
-            p1_projectile: None,
+            projectiles: vec![],
+            next_id: 0,
             max_size,
             update_time: Instant::now(),
+            rules,
+            infer_rules,
+            speed_total: 0f32,
+            speed_samples: 0,
         }
     }
 
+    //Leaves the environment untouched when the frame is malformed,
+    //time is the frame's time in seconds since the match started
     pub fn update(&mut self,
+                  time: f32,
                   bot: &TankData,
                   opp: &TankData,
-                  projectile_data: HashMap<String, Projectile>) {
+                  projectile_data: HashMap<String, Projectile>) -> Result<(), Error> {
+        let mut state = State::new(bot, opp, time)?;
+        let mut bullets = projectile_data
+            .iter()
+            .map(|(key, projectile)| Ok((key.clone(), Bullet::from_projectile(projectile)?)))
+            .collect::<Result<Vec<_>, Error>>()?;
+        //New projectiles get their ids in the same order every time
+        bullets.sort_by(|a, b| a.0.cmp(&b.0));
+
+        //An earlier time is a new match, the history and the motion start over
+        if self.history.back().map_or(false, |prev| time < prev.time) {
+            self.history.clear();
+        }
+        if let Some(prev) = self.history.back() {
+            state.bot_motion = prev.bot_motion.update(&prev.bot, &state.bot, time);
+            state.opp_motion = prev.opp_motion.update(&prev.opp, &state.opp, time);
+        }
+
         self.update_time = Instant::now();
-        if self.history.len() > self.max_size {
+        //The newest state is kept even with a max_size of 0
+        while self.history.len() >= self.max_size.max(1) {
             self.history.pop_front();
         }
-        if let Some(projectile) = projectile_data.get("p0") {
-            self.p0_projectile = Some(Bullet::from_projectile(projectile));
-        } else {
-            self.p0_projectile = None;
+        self.history.push_back(state);
+        self.track(time, bullets);
+        if self.infer_rules {
+            self.observe_speeds();
         }
-        if let Some(projectile) = projectile_data.get("p1") {
-            self.p1_projectile = Some(Bullet::from_projectile(projectile));
-        } else {
-            self.p1_projectile = None;
-        }
-        self.history.push_back(State::new(bot, opp));
+        Ok(())
     }
 
-    pub fn get_bot_bullet(&self, team: Team) -> Option<Bullet> {
-        match team {
-            Team::A => {
-                self.p0_projectile
-            }
-            Team::B => {
-                self.p1_projectile
+    fn track(&mut self, time: f32, bullets: Vec<(String, Bullet)>) {
+        let mut tracked = Vec::with_capacity(bullets.len());
+        for (key, bullet) in bullets {
+            let speed = self.rules.bullet_speed;
+            let same = self.projectiles.iter().position(|projectile| {
+                let reach = speed * (time - projectile.last_seen).max(0f32) + JUMP_TOLERANCE;
+                projectile.key == key && (bullet.pos() - projectile.bullet.pos()).sq_magnitude() <= reach * reach
+            });
+            match same {
+                Some(index) => {
+                    let mut projectile = self.projectiles.swap_remove(index);
+                    projectile.observe(time, bullet);
+                    tracked.push(projectile);
+                }
+                None => {
+                    tracked.push(TrackedProjectile {
+                        id: self.next_id,
+                        owner: TrackedProjectile::owner_from_key(&key),
+                        key,
+                        first_seen: time,
+                        last_seen: time,
+                        bullet,
+                        velocities: vec![(time, bullet.vel())],
+                    });
+                    self.next_id += 1;
+                }
             }
         }
+        tracked.sort_by_key(|projectile| projectile.id);
+        self.projectiles = tracked;
     }
 
-    pub fn get_enemy_bullet(&self, team: Team) -> Option<Bullet> {
-        match team {
-            Team::A => {
-                self.p1_projectile
+    fn observe_speeds(&mut self) {
+        for projectile in &self.projectiles {
+            let speed = projectile.bullet.vel().sq_magnitude().sqrt();
+            if speed > 0f32 {
+                self.speed_total += speed;
+                self.speed_samples += 1;
             }
-            Team::B => {
-                self.p0_projectile
+        }
+        if self.speed_samples >= MIN_SPEED_SAMPLES {
+            let speed = self.speed_total / self.speed_samples as f32;
+            if (speed - self.rules.bullet_speed).abs() > SPEED_TOLERANCE {
+                self.rules.bullet_speed = speed;
             }
         }
     }
 
+    pub fn rules(&self) -> GameRules {
+        self.rules
+    }
+
+    //Every projectile in the last frame, oldest first
+    pub fn projectiles(&self) -> &[TrackedProjectile] {
+        &self.projectiles
+    }
+
+    pub fn friendly_bullets(&self, team: Team) -> impl Iterator<Item=&TrackedProjectile> {
+        self.projectiles.iter().filter(move |projectile| projectile.owner == Some(team))
+    }
+
+    //Projectiles nobody can be told to own are taken to be hostile
+    pub fn hostile_bullets(&self, team: Team) -> impl Iterator<Item=&TrackedProjectile> {
+        self.projectiles.iter().filter(move |projectile| projectile.owner != Some(team))
+    }
+
+    //The newest of our bullets
+    pub fn get_bot_bullet(&self, team: Team) -> Option<Bullet> {
+        self.friendly_bullets(team).last().map(|projectile| projectile.bullet)
+    }
+
+    //The newest of the bullets that can hit us
+    pub fn get_enemy_bullet(&self, team: Team) -> Option<Bullet> {
+        self.hostile_bullets(team).last().map(|projectile| projectile.bullet)
+    }
+
     pub fn current_state(&self) -> Option<State> {
-        self.history.get(self.history.len() - 1).copied()
+        self.ago(0)
+    }
+
+    //The state the given number of frames before the current one
+    pub fn ago(&self, ticks: usize) -> Option<State> {
+        self.history
+            .len()
+            .checked_sub(ticks + 1)
+            .and_then(|index| self.history.get(index))
+            .copied()
+    }
+
+    //Interpolates between the frames around time, None outside the history
+    pub fn state_at(&self, time: f32) -> Option<State> {
+        let after = self.history.partition_point(|state| state.time < time);
+        let next = self.history.get(after)?;
+        if next.time == time {
+            return Some(*next);
+        }
+        let prev = self.history.get(after.checked_sub(1)?)?;
+        Some(prev.lerp(next, (time - prev.time) / (next.time - prev.time)))
+    }
+
+    //The last ticks states, oldest first
+    pub fn window(&self, ticks: usize) -> impl Iterator<Item=&State> {
+        self.history.iter().skip(self.history.len().saturating_sub(ticks))
+    }
+
+    //The states at or after time, oldest first
+    pub fn since(&self, time: f32) -> impl Iterator<Item=&State> {
+        self.history.iter().skip(self.history.partition_point(|state| state.time < time))
     }
 
     pub fn last_update(&self) -> Instant {
         self.update_time
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tank() -> TankData {
+        tank_at(100f32, 100f32, 0f32)
+    }
+
+    //r in degrees like the server sends it
+    fn tank_at(x: f32, y: f32, r: f32) -> TankData {
+        TankData {
+            x: x.to_string(),
+            y: y.to_string(),
+            r: r.to_string(),
+            can_fire: true,
+        }
+    }
+
+    fn projectile(x: f32, y: f32, vx: i32, vy: i32) -> Projectile {
+        Projectile {
+            x: x.to_string(),
+            y: y.to_string(),
+            vx,
+            vy,
+        }
+    }
+
+    fn frames(env: &mut Env, vx: i32, vy: i32) {
+        for tick in 0..20 {
+            let mut projectiles = HashMap::new();
+            projectiles.insert("p1".to_string(), projectile(300f32, 300f32, vx, vy));
+            env.update(tick as f32 / 30f32, &tank(), &tank(), projectiles).unwrap();
+        }
+    }
+
+    #[test]
+    fn bullet_speed_is_taken_from_projectiles() {
+        let mut env = Env::new(10);
+        frames(&mut env, 300, 400);
+        assert_eq!(env.rules().bullet_speed, 500f32);
+
+        //Rounding alone leaves the rules alone
+        let mut env = Env::new(10);
+        frames(&mut env, 283, 283);
+        assert_eq!(env.rules().bullet_speed, GameRules::default().bullet_speed);
+
+        let mut env = Env::with_rules(10, GameRules::default(), false);
+        frames(&mut env, 300, 400);
+        assert_eq!(env.rules().bullet_speed, GameRules::default().bullet_speed);
+    }
+
+    #[test]
+    fn owners_come_from_the_key() {
+        assert_eq!(TrackedProjectile::owner_from_key("p0"), Some(Team::A));
+        assert_eq!(TrackedProjectile::owner_from_key("p1"), Some(Team::B));
+        assert_eq!(TrackedProjectile::owner_from_key("p1_3"), Some(Team::B));
+        assert_eq!(TrackedProjectile::owner_from_key("p0-12"), Some(Team::A));
+        assert_eq!(TrackedProjectile::owner_from_key("p10"), None);
+        assert_eq!(TrackedProjectile::owner_from_key("bullet"), None);
+    }
+
+    #[test]
+    fn tracks_every_projectile() {
+        let mut env = Env::new(10);
+        let dt = 1f32 / 30f32;
+        let mut first = HashMap::new();
+        first.insert("p0_0".to_string(), projectile(100f32, 100f32, 400, 0));
+        first.insert("p0_1".to_string(), projectile(100f32, 300f32, 0, 400));
+        first.insert("p1_0".to_string(), projectile(900f32, 100f32, -400, 0));
+        first.insert("x".to_string(), projectile(500f32, 500f32, 0, -400));
+        env.update(0f32, &tank(), &tank(), first).unwrap();
+        let ids = env.projectiles().iter().map(|projectile| projectile.id).collect::<Vec<_>>();
+        assert_eq!(ids, vec![0, 1, 2, 3]);
+        assert_eq!(env.friendly_bullets(Team::A).count(), 2);
+        assert_eq!(env.hostile_bullets(Team::A).count(), 2);
+        assert_eq!(env.friendly_bullets(Team::B).count(), 1);
+        assert_eq!(env.hostile_bullets(Team::B).count(), 3);
+
+        //One moves on and bounces, one is gone and a new shot reuses the key of another
+        let mut second = HashMap::new();
+        second.insert("p0_0".to_string(), projectile(113f32, 100f32, -400, 0));
+        second.insert("p1_0".to_string(), projectile(700f32, 650f32, 0, 400));
+        env.update(dt, &tank(), &tank(), second).unwrap();
+        let projectiles = env.projectiles();
+        assert_eq!(projectiles.len(), 2);
+        assert_eq!(projectiles[0].id, 0);
+        assert_eq!(projectiles[0].first_seen, 0f32);
+        assert_eq!(projectiles[0].last_seen, dt);
+        assert_eq!(projectiles[0].velocities.len(), 2);
+        assert_eq!(projectiles[0].velocities[1], (dt, Vec2::new(-400f32, 0f32)));
+        assert_eq!(projectiles[1].id, 4);
+        assert_eq!(projectiles[1].first_seen, dt);
+        assert_eq!(projectiles[1].owner, Some(Team::B));
+        assert_eq!(env.get_bot_bullet(Team::B).unwrap().pos(), Vec2::new(700f32, 650f32));
+    }
+
+    #[test]
+    fn estimates_how_the_tanks_move() {
+        let mut env = Env::new(10);
+        let dt = 1f32 / 30f32;
+        //Driving forward at 150px/s while turning at 1rad/s, the opponent stays put
+        let (mut pos, mut r) = (Vec2::new(300f32, 300f32), 0f32);
+        for tick in 0..60 {
+            let bot = tank_at(pos.x(), pos.y(), r.to_degrees());
+            env.update(tick as f32 * dt, &bot, &tank(), HashMap::new()).unwrap();
+            if tick == 0 {
+                assert_eq!(env.current_state().unwrap().bot_motion.speed, 0f32);
+            }
+            let turned = r + dt;
+            pos += Vec2::from_angle(-(r + turned) * 0.5f32) * (150f32 * dt);
+            r = turned;
+        }
+        let state = env.current_state().unwrap();
+        let motion = state.bot_motion;
+        assert_eq!(motion.time, state.time);
+        assert!((motion.speed - 150f32).abs() < 1f32, "{:?}", motion);
+        assert!((motion.angular_vel - 1f32).abs() < 0.01f32, "{:?}", motion);
+        assert!(motion.acceleration.abs() < 1f32, "{:?}", motion);
+        assert!((motion.vel.sq_magnitude().sqrt() - 150f32).abs() < 1f32, "{:?}", motion);
+        assert_eq!(state.opp_motion.speed, 0f32);
+    }
+
+    //The bot drives along x at 30px a frame, one frame every 0.1s
+    fn drive(env: &mut Env, frames: usize) {
+        for tick in 0..frames {
+            let bot = tank_at(100f32 + tick as f32 * 30f32, 400f32, 0f32);
+            env.update(tick as f32 * 0.1f32, &bot, &tank(), HashMap::new()).unwrap();
+        }
+    }
+
+    #[test]
+    fn history_keeps_max_size_states() {
+        let mut env = Env::new(10);
+        assert!(env.current_state().is_none());
+        assert!(env.ago(0).is_none());
+        drive(&mut env, 15);
+        assert_eq!(env.window(100).count(), 10);
+        assert_eq!(env.current_state().unwrap().time, 1.4f32);
+        assert_eq!(env.ago(9).unwrap().time, 0.5f32);
+        assert!(env.ago(10).is_none());
+
+        let mut env = Env::new(0);
+        drive(&mut env, 3);
+        assert_eq!(env.window(100).count(), 1);
+        assert_eq!(env.current_state().unwrap().time, 0.2f32);
+
+        //A new match starts the history over
+        let mut env = Env::new(10);
+        drive(&mut env, 5);
+        drive(&mut env, 2);
+        assert_eq!(env.window(100).count(), 2);
+    }
+
+    #[test]
+    fn iterates_over_a_window() {
+        let mut env = Env::new(10);
+        drive(&mut env, 6);
+        let times = |states: Vec<&State>| states.iter().map(|state| state.time).collect::<Vec<_>>();
+        assert_eq!(times(env.window(3).collect()), vec![0.3f32, 0.4f32, 0.5f32]);
+        assert_eq!(times(env.window(0).collect()), Vec::<f32>::new());
+        assert_eq!(times(env.since(0.35f32).collect()), vec![0.4f32, 0.5f32]);
+        assert_eq!(times(env.since(0.4f32).collect()), vec![0.4f32, 0.5f32]);
+        assert_eq!(env.since(1f32).count(), 0);
+    }
+
+    #[test]
+    fn interpolates_between_frames() {
+        let mut env = Env::new(10);
+        drive(&mut env, 4);
+        assert!(env.state_at(-0.05f32).is_none());
+        assert!(env.state_at(0.35f32).is_none());
+        assert_eq!(env.state_at(0.2f32).unwrap().bot.pos, Vec2::new(160f32, 400f32));
+        let state = env.state_at(0.25f32).unwrap();
+        assert!((state.time - 0.25f32).abs() < 1e-6f32);
+        assert!((state.bot.pos - Vec2::new(175f32, 400f32)).sq_magnitude() < 1e-6f32);
+        assert!((state.dist - (state.bot.pos - state.opp.pos).sq_magnitude().sqrt()).abs() < 1e-3f32);
+
+        //Turns the short way round
+        let mut env = Env::new(10);
+        env.update(0f32, &tank_at(0f32, 0f32, 350f32), &tank(), HashMap::new()).unwrap();
+        env.update(1f32, &tank_at(0f32, 0f32, 10f32), &tank(), HashMap::new()).unwrap();
+        let r = env.state_at(0.5f32).unwrap().bot.r;
+        assert!(crate::r_core::kinematics::wrap(r).abs() < 1e-4f32, "{}", r);
+    }
+
+    #[test]
+    fn malformed_frames_change_nothing() {
+        let mut env = Env::new(10);
+        let mut projectiles = HashMap::new();
+        projectiles.insert("p0".to_string(), projectile(300f32, 300f32, 400, 0));
+        env.update(0f32, &tank(), &tank(), projectiles).unwrap();
+
+        let mut projectiles = HashMap::new();
+        projectiles.insert("p0".to_string(), projectile(313f32, 300f32, 400, 0));
+        projectiles.insert("p1".to_string(), Projectile {
+            x: "NaN".to_string(),
+            y: "0".to_string(),
+            vx: 0,
+            vy: 0,
+        });
+        assert!(matches!(env.update(0.1f32, &tank(), &tank(), projectiles), Err(Error::Parse { field: "projectile x", .. })));
+        let mut projectiles = HashMap::new();
+        projectiles.insert("p0".to_string(), projectile(313f32, 300f32, 400, 0));
+        assert!(matches!(env.update(0.1f32, &tank(), &tank_at(0f32, 0f32, f32::NAN), projectiles), Err(Error::Parse { field: "tank r", .. })));
+
+        assert_eq!(env.window(10).count(), 1);
+        assert_eq!(env.current_state().unwrap().time, 0f32);
+        assert_eq!(env.projectiles().len(), 1);
+        assert_eq!(env.projectiles()[0].bullet.pos(), Vec2::new(300f32, 300f32));
+        assert_eq!(env.projectiles()[0].last_seen, 0f32);
+    }
+}
@@ -0,0 +1,43 @@
+use std::fmt;
+
+#[derive(Debug)]
+pub enum Error {
+    //A numeric field of the game data did not hold a finite number
+    Parse {
+        field: &'static str,
+        value: String,
+    },
+    //The server answered with a body that is not valid game data
+    Decode(reqwest::Error),
+    //The request itself failed, the connection needs to be retried
+    Transport(reqwest::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Parse { field, value } => write!(f, "invalid value {:?} for {}", value, field),
+            Error::Decode(err) => write!(f, "malformed game data: {}", err),
+            Error::Transport(err) => write!(f, "request failed: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Parse { .. } => None,
+            Error::Decode(err) | Error::Transport(err) => Some(err),
+        }
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(err: reqwest::Error) -> Self {
+        if err.is_decode() {
+            Error::Decode(err)
+        } else {
+            Error::Transport(err)
+        }
+    }
+}
@@ -0,0 +1,302 @@
+use crate::r_core::controller::strategy::Action;
+use crate::r_core::math::vec2::Vec2;
+use crate::r_core::state::{BotData, State};
+use crate::r_core::rules::GameRules;
+
+const PI: f32 = std::f32::consts::PI;
+
+//How much each new sample moves an estimate
+const SMOOTHING: f32 = 0.2f32;
+//Below this change per frame the tank counts as moving at full speed
+const STEADY: f32 = 0.05f32;
+//A tank moving slower than this share of its speed is taken to be stuck on a wall
+const BLOCKED: f32 = 0.1f32;
+
+//Where a tank is and how fast it is moving along and around its heading
+#[derive(Debug, Copy, Clone)]
+pub struct Motion {
+    pub pos: Vec2,
+    pub rot: f32,
+    pub speed: f32,
+    pub turn: f32,
+}
+
+pub(crate) fn wrap(angle: f32) -> f32 {
+    let angle = angle % (PI * 2f32);
+    if angle > PI {
+        angle - PI * 2f32
+    } else if angle < -PI {
+        angle + PI * 2f32
+    } else {
+        angle
+    }
+}
+
+//Moves value towards target by at most step
+fn approach(value: f32, target: f32, step: f32) -> f32 {
+    if (target - value).abs() <= step {
+        target
+    } else {
+        value + (target - value).signum() * step
+    }
+}
+
+//Estimates how our tank responds to commands from the states seen after sending them.
+//Starts out from the rules' speeds with instant acceleration.
+#[derive(Debug, Copy, Clone)]
+pub struct Kinematics {
+    speed: f32,
+    turn_rate: f32,
+    acceleration: f32,
+    turn_acceleration: f32,
+    last: Option<(BotData, f32)>,
+    motion: Option<Motion>,
+    command: Option<Action>,
+}
+
+impl Kinematics {
+    pub fn new() -> Self {
+        Self::with_rules(&GameRules::default())
+    }
+
+    pub fn with_rules(rules: &GameRules) -> Self {
+        Self {
+            speed: rules.tank_speed,
+            turn_rate: rules.turn_rate,
+            acceleration: f32::INFINITY,
+            turn_acceleration: f32::INFINITY,
+            last: None,
+            motion: None,
+            command: None,
+        }
+    }
+
+    pub fn speed(&self) -> f32 {
+        self.speed
+    }
+
+    pub fn turn_rate(&self) -> f32 {
+        self.turn_rate
+    }
+
+    pub fn acceleration(&self) -> f32 {
+        self.acceleration
+    }
+
+    pub fn turn_acceleration(&self) -> f32 {
+        self.turn_acceleration
+    }
+
+    //Our tank as of the last observed state
+    pub fn motion(&self) -> Option<Motion> {
+        self.motion
+    }
+
+    //Speed along the heading and turn speed between two observations of a tank
+    pub fn measure(from: &BotData, to: &BotData, dt: f32) -> (f32, f32) {
+        let turned = wrap(to.r - from.r);
+        let forward = Vec2::from_angle(-(from.r + turned * 0.5f32));
+        (forward.dot(to.pos - from.pos) / dt, turned / dt)
+    }
+
+    //Call with every new state and the command that was sent before it. Only frames where the
+    //command was the same as the one before are used, the server may apply a new command late.
+    pub fn observe(&mut self, state: &State, command: Action) {
+        let bot = state.bot;
+        let stable = self.command.map_or(false, |last| last.move_dir == command.move_dir && last.turn == command.turn);
+        self.command = Some(command);
+        let (last, last_time) = match self.last {
+            Some(last) if state.time > last.1 => last,
+            Some(last) if state.time == last.1 => return,
+            _ => {
+                self.last = Some((bot, state.time));
+                self.motion = Some(Motion {
+                    pos: bot.pos,
+                    rot: bot.r,
+                    speed: 0f32,
+                    turn: 0f32,
+                });
+                return;
+            }
+        };
+        let dt = state.time - last_time;
+        let (speed, turn) = Self::measure(&last, &bot, dt);
+        let previous = self.motion.map_or((0f32, 0f32), |motion| (motion.speed, motion.turn));
+        self.last = Some((bot, state.time));
+        self.motion = Some(Motion {
+            pos: bot.pos,
+            rot: bot.r,
+            speed,
+            turn,
+        });
+        if !stable {
+            return;
+        }
+
+        let speed_change = (speed - previous.0).abs();
+        if command.move_dir != 0f32 && speed.abs() > BLOCKED * self.speed {
+            if speed_change <= STEADY * self.speed {
+                let sample = (speed / command.move_dir).abs();
+                self.speed += (sample - self.speed) * SMOOTHING;
+            } else {
+                self.acceleration = Self::blend(self.acceleration, speed_change / dt);
+            }
+        }
+        let turn_change = (turn - previous.1).abs();
+        if command.turn != 0f32 && turn.abs() > BLOCKED * self.turn_rate {
+            if turn_change <= STEADY * self.turn_rate {
+                let sample = (turn / command.turn).abs();
+                self.turn_rate += (sample - self.turn_rate) * SMOOTHING;
+            } else {
+                self.turn_acceleration = Self::blend(self.turn_acceleration, turn_change / dt);
+            }
+        }
+    }
+
+    fn blend(estimate: f32, sample: f32) -> f32 {
+        if estimate.is_finite() {
+            estimate + (sample - estimate) * SMOOTHING
+        } else {
+            sample
+        }
+    }
+
+    //Moves a tank already going at the commanded speed, walls are not considered
+    pub fn predict(&self, pos: Vec2, rot: f32, action: Action, dt: f32) -> (Vec2, f32) {
+        let motion = self.advance(Motion {
+            pos,
+            rot,
+            speed: action.move_dir * self.speed,
+            turn: action.turn * self.turn_rate,
+        }, action, dt);
+        (motion.pos, motion.rot)
+    }
+
+    //Accelerates towards the commanded speeds and moves for dt
+    pub fn advance(&self, motion: Motion, action: Action, dt: f32) -> Motion {
+        let speed = approach(motion.speed, action.move_dir * self.speed, self.acceleration * dt);
+        let turn = approach(motion.turn, action.turn * self.turn_rate, self.turn_acceleration * dt);
+        let rot = motion.rot + (motion.turn + turn) * 0.5f32 * dt;
+        let heading = motion.rot + (rot - motion.rot) * 0.5f32;
+        Motion {
+            pos: motion.pos + Vec2::from_angle(-heading) * ((motion.speed + speed) * 0.5f32 * dt),
+            rot,
+            speed,
+            turn,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::r_core::map::map::Map;
+    use crate::r_core::sim::sim::Simulator;
+    use crate::r_core::controller::controller::OutputAction;
+    use crate::r_core::env::Team;
+    use crate::r_core::state::TankMotion;
+    use std::sync::Arc;
+
+    fn state(sim: &Simulator) -> State {
+        let tank = sim.tank(Team::A);
+        let opp = sim.tank(Team::B);
+        let bot = BotData {
+            pos: tank.pos,
+            r: tank.rot,
+            can_fire: true,
+        };
+        let opp = BotData {
+            pos: opp.pos,
+            r: opp.rot,
+            can_fire: true,
+        };
+        State {
+            bot,
+            opp,
+            dist: (opp.pos - bot.pos).sq_magnitude().sqrt(),
+            abs_bearing: (opp.pos - bot.pos).angle(),
+            time: sim.time(),
+            bot_motion: TankMotion::still(sim.time()),
+            opp_motion: TankMotion::still(sim.time()),
+        }
+    }
+
+    #[test]
+    fn learns_the_simulators_speeds() {
+        let rules = GameRules {
+            tank_speed: 200f32,
+            turn_rate: 2f32,
+            ..GameRules::default()
+        };
+        let map = Arc::new(Map::new(vec![]).with_rules(rules));
+        let mut sim = Simulator::new(map, Vec2::new(300f32, 360f32), Vec2::new(1000f32, 360f32));
+        let mut kinematics = Kinematics::new();
+        assert_eq!(kinematics.speed(), GameRules::default().tank_speed);
+        let dt = 1f32 / 30f32;
+        for tick in 0..120 {
+            let action = Action {
+                move_dir: if tick % 60 < 30 { 1f32 } else { -1f32 },
+                turn: 1f32,
+                fire: false,
+            };
+            let out = OutputAction {
+                m: action.move_dir as i32,
+                r: -action.turn as i32,
+                f: 0,
+            };
+            sim.step(out, OutputAction::new(), dt);
+            kinematics.observe(&state(&sim), action);
+        }
+        assert!((kinematics.speed() - rules.tank_speed).abs() < 1f32, "{}", kinematics.speed());
+        assert!((kinematics.turn_rate() - rules.turn_rate).abs() < 0.05f32, "{}", kinematics.turn_rate());
+        assert_eq!(Kinematics::with_rules(&rules).speed(), rules.tank_speed);
+    }
+
+    #[test]
+    fn predict_follows_the_simulator() {
+        let map = Arc::new(Map::new(vec![]));
+        let mut sim = Simulator::new(map, Vec2::new(300f32, 360f32), Vec2::new(1000f32, 360f32));
+        let kinematics = Kinematics::new();
+        let action = Action {
+            move_dir: 1f32,
+            turn: -1f32,
+            fire: false,
+        };
+        let start = *sim.tank(Team::A);
+        let dt = 1f32 / 60f32;
+        for _ in 0..30 {
+            sim.step(OutputAction { m: 1, r: 1, f: 0 }, OutputAction::new(), dt);
+        }
+        let (mut pos, mut rot) = (start.pos, start.rot);
+        for _ in 0..30 {
+            let next = kinematics.predict(pos, rot, action, dt);
+            pos = next.0;
+            rot = next.1;
+        }
+        let tank = sim.tank(Team::A);
+        assert!((tank.pos - pos).sq_magnitude().sqrt() < 2f32, "{:?} {:?}", tank.pos, pos);
+        assert!((tank.rot - rot).abs() < 1e-3f32);
+    }
+
+    #[test]
+    fn advance_respects_acceleration() {
+        let mut kinematics = Kinematics::new();
+        kinematics.acceleration = 300f32;
+        let action = Action {
+            move_dir: 1f32,
+            turn: 0f32,
+            fire: false,
+        };
+        let mut motion = Motion {
+            pos: Vec2::new(0f32, 0f32),
+            rot: 0f32,
+            speed: 0f32,
+            turn: 0f32,
+        };
+        motion = kinematics.advance(motion, action, 0.25f32);
+        assert_eq!(motion.speed, 75f32);
+        motion = kinematics.advance(motion, action, 0.5f32);
+        assert_eq!(motion.speed, GameRules::default().tank_speed);
+        assert!((motion.pos.x() - (75f32 * 0.5f32 * 0.25f32 + 112.5f32 * 0.5f32)).abs() < 1e-3f32);
+    }
+}
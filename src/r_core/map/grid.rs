@@ -0,0 +1,265 @@
+use crate::r_core::map::map::Shape;
+use crate::r_core::math::vec2::Vec2;
+
+//Uniform grid over the shapes' bounding boxes, each cell lists the shapes overlapping it
+#[derive(Debug, Clone)]
+pub struct Grid {
+    origin: Vec2,
+    cell_size: f32,
+    cols: usize,
+    rows: usize,
+    cells: Vec<Vec<usize>>,
+}
+
+impl Grid {
+    pub fn new(shapes: &[Shape], cell_size: f32) -> Self {
+        let mut min = Vec2::new(f32::INFINITY, f32::INFINITY);
+        let mut max = Vec2::new(f32::NEG_INFINITY, f32::NEG_INFINITY);
+        for shape in shapes {
+            let (lo, hi) = shape.bounds();
+            min = Vec2::new(min.x().min(lo.x()), min.y().min(lo.y()));
+            max = Vec2::new(max.x().max(hi.x()), max.y().max(hi.y()));
+        }
+        if shapes.is_empty() {
+            min = Vec2::new(0f32, 0f32);
+            max = min;
+        }
+        let cols = ((max.x() - min.x()) / cell_size).floor() as usize + 1;
+        let rows = ((max.y() - min.y()) / cell_size).floor() as usize + 1;
+        let mut grid = Self {
+            origin: min,
+            cell_size,
+            cols,
+            rows,
+            cells: vec![vec![]; cols * rows],
+        };
+        for (index, shape) in shapes.iter().enumerate() {
+            let (lo, hi) = shape.bounds();
+            let (x0, y0) = grid.clamped_cell(lo);
+            let (x1, y1) = grid.clamped_cell(hi);
+            for y in y0..=y1 {
+                for x in x0..=x1 {
+                    grid.cells[y * cols + x].push(index);
+                }
+            }
+        }
+        grid
+    }
+
+    fn clamped_cell(&self, point: Vec2) -> (usize, usize) {
+        let local = (point - self.origin) / self.cell_size;
+        let x = (local.x().floor().max(0f32) as usize).min(self.cols - 1);
+        let y = (local.y().floor().max(0f32) as usize).min(self.rows - 1);
+        (x, y)
+    }
+
+    //Shapes that may contain the point
+    pub fn candidates(&self, point: Vec2) -> &[usize] {
+        let local = (point - self.origin) / self.cell_size;
+        if local.x() < 0f32 || local.y() < 0f32 {
+            return &[];
+        }
+        let (x, y) = (local.x() as usize, local.y() as usize);
+        if x >= self.cols || y >= self.rows {
+            return &[];
+        }
+        &self.cells[y * self.cols + x]
+    }
+
+    //Shapes whose cells overlap the box from lo to hi, each once
+    pub fn overlapping(&self, lo: Vec2, hi: Vec2) -> Vec<usize> {
+        let size = Vec2::new(self.cols as f32, self.rows as f32) * self.cell_size;
+        let end = self.origin + size;
+        if hi.x() < self.origin.x() || hi.y() < self.origin.y() || lo.x() > end.x() || lo.y() > end.y() {
+            return vec![];
+        }
+        let (x0, y0) = self.clamped_cell(lo);
+        let (x1, y1) = self.clamped_cell(hi);
+        let mut found = vec![];
+        for y in y0..=y1 {
+            for x in x0..=x1 {
+                found.extend_from_slice(&self.cells[y * self.cols + x]);
+            }
+        }
+        found.sort_unstable();
+        found.dedup();
+        found
+    }
+
+    //Walks the cells the ray passes through between min_dist and max_dist in order,
+    //giving visit each cell's shapes and the distance at which the ray leaves it.
+    //Stops early once visit returns true.
+    pub fn traverse<F: FnMut(&[usize], f32) -> bool>(&self, source: Vec2, dir: Vec2, min_dist: f32, max_dist: f32, mut visit: F) {
+        let size = Vec2::new(self.cols as f32, self.rows as f32) * self.cell_size;
+        let bounds = Shape::Rect {
+            pos: self.origin + size * 0.5f32,
+            w_h: size * 0.5f32,
+        };
+        let (t_in, t_out, _) = match bounds.ray_interval(source, dir) {
+            Some(interval) => interval,
+            None => return
+        };
+        let start = t_in.max(min_dist);
+        let end = t_out.min(max_dist);
+        if start > end {
+            return;
+        }
+        let (mut x, mut y) = self.clamped_cell(source + dir * start);
+
+        let axis = |o: f32, d: f32, cell: usize, origin: f32| -> (f32, f32) {
+            if d > 0f32 {
+                ((origin + (cell + 1) as f32 * self.cell_size - o) / d, self.cell_size / d)
+            } else if d < 0f32 {
+                ((origin + cell as f32 * self.cell_size - o) / d, -self.cell_size / d)
+            } else {
+                (f32::INFINITY, f32::INFINITY)
+            }
+        };
+        let (mut next_x, delta_x) = axis(source.x(), dir.x(), x, self.origin.x());
+        let (mut next_y, delta_y) = axis(source.y(), dir.y(), y, self.origin.y());
+        loop {
+            let exit = next_x.min(next_y);
+            if visit(&self.cells[y * self.cols + x], exit) || exit >= end {
+                return;
+            }
+            if next_x < next_y {
+                if dir.x() > 0f32 {
+                    x += 1;
+                } else {
+                    x = x.wrapping_sub(1);
+                }
+                next_x += delta_x;
+            } else {
+                if dir.y() > 0f32 {
+                    y += 1;
+                } else {
+                    y = y.wrapping_sub(1);
+                }
+                next_y += delta_y;
+            }
+            if x >= self.cols || y >= self.rows {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate test;
+
+    use super::Grid;
+    use crate::r_core::map::map::{Map, Shape, RayHit};
+    use crate::r_core::math::vec2::Vec2;
+    use test::Bencher;
+
+    const MAPS: [&str; 3] = ["./maps/MapA.json", "./maps/MapB.json", "./maps/MapC.json"];
+
+    fn linear_intersects(map: &Map, point: Vec2) -> bool {
+        map.shapes.iter().any(|shape| shape.intersects(point))
+    }
+
+    fn linear_cast(map: &Map, source: Vec2, dir: Vec2, min_dist: f32, max_dist: f32) -> Option<(Shape, RayHit)> {
+        let mut closest: Option<(Shape, RayHit)> = None;
+        for shape in map.shapes.iter() {
+            let limit = closest.map_or(max_dist, |(_, hit)| hit.dist);
+            if let Some(hit) = shape.ray_hit(source, dir, min_dist, limit) {
+                if closest.map_or(true, |(_, best)| hit.dist < best.dist) {
+                    closest = Some((*shape, hit));
+                }
+            }
+        }
+        closest
+    }
+
+    //Deterministic points spread over and slightly beyond the arena
+    fn points(count: usize) -> Vec<Vec2> {
+        let mut seed = 0x2545_f491u32;
+        let mut next = move || {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            seed as f32 / u32::MAX as f32
+        };
+        (0..count)
+            .map(|_| Vec2::new(next() * 1480f32 - 100f32, next() * 920f32 - 100f32))
+            .collect()
+    }
+
+    #[test]
+    fn intersects_matches_linear_scan() {
+        for path in &MAPS {
+            let map = Map::map_from_file(path);
+            for point in points(20000) {
+                assert_eq!(map.intersects(point), linear_intersects(&map, point), "{} {:?}", path, point);
+            }
+        }
+    }
+
+    #[test]
+    fn cast_matches_linear_scan() {
+        for path in &MAPS {
+            let map = Map::map_from_file(path);
+            for (index, source) in points(4000).into_iter().enumerate() {
+                let dir = Vec2::from_angle(index as f32 * 0.731f32);
+                let min_dist = (index % 3) as f32 * 20f32;
+                let grid = map.cast(source, dir, min_dist, 2400f32).map(|(_, hit)| hit.dist);
+                let linear = linear_cast(&map, source, dir, min_dist, 2400f32).map(|(_, hit)| hit.dist);
+                assert_eq!(grid, linear, "{} {:?} {:?}", path, source, dir);
+            }
+        }
+    }
+
+    #[test]
+    fn overlapping_finds_every_touching_box() {
+        for path in &MAPS {
+            let map = Map::map_from_file(path);
+            let grid = Grid::new(&map.shapes, 64f32);
+            for (index, lo) in points(4000).into_iter().enumerate() {
+                let hi = lo + Vec2::new((index % 7) as f32 * 30f32, (index % 5) as f32 * 40f32);
+                let found = grid.overlapping(lo, hi);
+                for (shape_index, shape) in map.shapes.iter().enumerate() {
+                    let (shape_lo, shape_hi) = shape.bounds();
+                    let touches = shape_hi.x() >= lo.x() && shape_hi.y() >= lo.y() && shape_lo.x() <= hi.x() && shape_lo.y() <= hi.y();
+                    assert!(!touches || found.contains(&shape_index), "{} {:?} {:?}", path, lo, hi);
+                }
+            }
+        }
+    }
+
+    #[bench]
+    fn bench_intersects_linear(b: &mut Bencher) {
+        let map = Map::map_from_file(MAPS[2]);
+        let points = points(1000);
+        b.iter(|| points.iter().filter(|&&point| linear_intersects(&map, point)).count());
+    }
+
+    #[bench]
+    fn bench_intersects_grid(b: &mut Bencher) {
+        let map = Map::map_from_file(MAPS[2]);
+        let points = points(1000);
+        b.iter(|| points.iter().filter(|&&point| map.intersects(point)).count());
+    }
+
+    #[bench]
+    fn bench_cast_linear(b: &mut Bencher) {
+        let map = Map::map_from_file(MAPS[2]);
+        let points = points(1000);
+        b.iter(|| points
+            .iter()
+            .enumerate()
+            .filter_map(|(index, &source)| linear_cast(&map, source, Vec2::from_angle(index as f32), 0f32, 2400f32))
+            .count());
+    }
+
+    #[bench]
+    fn bench_cast_grid(b: &mut Bencher) {
+        let map = Map::map_from_file(MAPS[2]);
+        let points = points(1000);
+        b.iter(|| points
+            .iter()
+            .enumerate()
+            .filter_map(|(index, &source)| map.cast(source, Vec2::from_angle(index as f32), 0f32, 2400f32))
+            .count());
+    }
+}
@@ -1,6 +1,8 @@
 use crate::r_core::math::vec2::Vec2;
 use crate::r_core::state::Bullet;
 use crate::r_core::map::map::Shape::{Rect, Circle, RotRect};
+use crate::r_core::map::grid::Grid;
+use crate::r_core::rules::GameRules;
 use std::cmp::Ordering;
 use std::ops::Range;
 use std::sync::Arc;
@@ -10,48 +12,199 @@ use serde::{Serialize, Deserialize};
 #[derive(Debug, Clone)]
 pub struct Map {
     pub shapes: Arc<Vec<Shape>>,
+    grid: Arc<Grid>,
+    rules: GameRules,
 }
 
-const BULLET_VEL: f32 = 400f32;
-const MAX_DIST: f32 = BULLET_VEL * 6f32;
+//Keeps a bounced ray from hitting the face it just left
+const BOUNCE_CLEARANCE: f32 = 1e-2;
+const GRID_CELL: f32 = 64f32;
+//Hits closer than this to both faces of a rectangle count as corner hits
+const CORNER_TOLERANCE: f32 = 0.5f32;
+//Bank shots are looked for between angles this many steps apart around the circle, then refined
+const BANK_SWEEP: usize = 720;
+const BANK_REFINE: usize = 24;
+//How close a refined bank shot has to pass the target
+const BANK_TOLERANCE: f32 = 0.5f32;
+//Time between the positions checked against a tank, a bullet moves 4px in it
+const STRIKE_STEP: f32 = 0.01f32;
+const REACH_TOLERANCE: f32 = 0.5f32;
 
 #[derive(Debug, Clone)]
 pub struct BulletTrajectory {
     bounces: Vec<Vec2>,
     times: Vec<f32>,
+    speed: f32,
 }
 
 impl BulletTrajectory {
-    pub fn new(bounces: Vec<Vec2>) -> Self {
+    pub fn new(bounces: Vec<Vec2>, speed: f32) -> Self {
         let mut times = vec![0f32];
-        let prev = bounces[0];
-        let mut dist_so_far = 0f32;
-        for bounce in bounces.iter().skip(1) {
-            let extra = (prev - *bounce).sq_magnitude().sqrt() / BULLET_VEL;
-            times.push(dist_so_far + extra);
-            dist_so_far += extra;
+        for pair in bounces.windows(2) {
+            let extra = (pair[1] - pair[0]).sq_magnitude().sqrt() / speed;
+            times.push(times[times.len() - 1] + extra);
         }
         Self {
             bounces,
             times,
+            speed,
         }
     }
 
+    //Index of the segment the bullet is on at a time, the last one from its end on
+    fn segment_at(&self, time: f32) -> usize {
+        self.times
+            .partition_point(|&start| start <= time)
+            .max(1)
+            .min(self.times.len().max(2) - 1) - 1
+    }
+
+    //None before the bullet is fired and after it is gone
     pub fn position_in(&self, time: f32) -> Option<Vec2> {
-        let index = self.times
-            .binary_search_by(|value| value
-                .partial_cmp(&time)
-                .unwrap_or(Ordering::Equal))
-            .unwrap_or_else(|x| x);
-        if index == 0 {
-            Some(self.bounces[0])
-        } else if index < self.times.len() - 1 {
-            let way_through = (time - self.times[index - 1]) / (self.times[index - 1] - self.times[index]);
-            Some(self.bounces[index - 1] + (self.bounces[index] - self.bounces[index - 1]) * way_through)
+        if time < 0f32 || time > self.lifetime() {
+            return None;
+        }
+        if self.bounces.len() < 2 {
+            return Some(self.bounces[0]);
+        }
+        let index = self.segment_at(time);
+        let duration = self.times[index + 1] - self.times[index];
+        let way_through = if duration > 0f32 { (time - self.times[index]) / duration } else { 0f32 };
+        Some(self.bounces[index] + (self.bounces[index + 1] - self.bounces[index]) * way_through)
+    }
+
+    pub fn velocity(&self, segment: usize) -> Option<Vec2> {
+        if segment + 1 >= self.bounces.len() {
+            return None;
+        }
+        let mut dir = self.bounces[segment + 1] - self.bounces[segment];
+        let length = dir.sq_magnitude().sqrt();
+        if length > 0f32 {
+            dir /= length;
+        }
+        Some(dir * self.speed)
+    }
+
+    pub fn velocity_in(&self, time: f32) -> Option<Vec2> {
+        if time < 0f32 || time > self.lifetime() {
+            return None;
+        }
+        self.velocity(self.segment_at(time))
+    }
+
+    //Bounces made by a time, one made exactly then included
+    pub fn bounces_at(&self, time: f32) -> usize {
+        if time <= 0f32 {
+            0
         } else {
-            None
+            self.segment_at(time)
         }
     }
+
+    //How long the bullet lasts before it stops bouncing or runs out of range
+    pub fn lifetime(&self) -> f32 {
+        self.times[self.times.len() - 1]
+    }
+
+    //The first time the bullet passes through a point, or within REACH_TOLERANCE of it
+    pub fn time_to_reach(&self, point: Vec2) -> Option<f32> {
+        for (index, pair) in self.bounces.windows(2).enumerate() {
+            let mut dir = pair[1] - pair[0];
+            let length = dir.sq_magnitude().sqrt();
+            if length == 0f32 {
+                continue;
+            }
+            dir /= length;
+            let along = dir.dot(point - pair[0]).max(0f32).min(length);
+            if (pair[0] + dir * along - point).sq_magnitude() <= REACH_TOLERANCE * REACH_TOLERANCE {
+                return Some(self.times[index] + along / self.speed);
+            }
+        }
+        None
+    }
+
+    pub fn bounces(&self) -> &[Vec2] {
+        &self.bounces
+    }
+
+    //Positions after every step of distance along the bounce chain, with the number of bounces
+    //made so far. None once the bullet is gone.
+    pub fn sample(&self, step: f32, count: usize) -> Vec<Option<(Vec2, usize)>> {
+        (1..=count)
+            .map(|index| {
+                let time = index as f32 * step / self.speed;
+                self.position_in(time).map(|pos| (pos, self.bounces_at(time)))
+            })
+            .collect()
+    }
+
+    //When the bullet first touches a hull, checked up to until seconds after it was at the start.
+    //hull gives the tank's shape at a time.
+    pub fn strikes<F: Fn(f32) -> Shape>(&self, hull: F, until: f32) -> Option<f32> {
+        self.first_hit(hull, until, true)
+    }
+
+    //Like strikes, for a bullet fired from inside the hull. It only counts once it has left.
+    pub fn returns_to<F: Fn(f32) -> Shape>(&self, hull: F, until: f32) -> Option<f32> {
+        self.first_hit(hull, until, false)
+    }
+
+    fn first_hit<F: Fn(f32) -> Shape>(&self, hull: F, until: f32, mut armed: bool) -> Option<f32> {
+        let count = (until / STRIKE_STEP).ceil() as usize;
+        for (index, sample) in self.sample(self.speed * STRIKE_STEP, count).into_iter().enumerate() {
+            let pos = match sample {
+                Some((pos, _)) => pos,
+                None => break
+            };
+            let time = (index + 1) as f32 * STRIKE_STEP;
+            if !hull(time).intersects(pos) {
+                armed = true;
+            } else if armed {
+                return Some(time);
+            }
+        }
+        None
+    }
+
+    //How far to the left of the given leg the target is and how far the bullet has travelled once
+    //it is level with it. None if the leg ends before or starts after the target.
+    fn passes(&self, target: Vec2, leg: usize) -> Option<(f32, f32)> {
+        if leg + 1 >= self.bounces.len() {
+            return None;
+        }
+        let start = self.bounces[leg];
+        let mut dir = self.bounces[leg + 1] - start;
+        let length = dir.sq_magnitude().sqrt();
+        if length == 0f32 {
+            return None;
+        }
+        dir /= length;
+        let offset = target - start;
+        let along = dir.dot(offset);
+        if along < 0f32 || along > length {
+            return None;
+        }
+        let before = self.bounces[..=leg]
+            .windows(2)
+            .map(|pair| (pair[1] - pair[0]).sq_magnitude().sqrt())
+            .sum::<f32>();
+        Some((dir.x() * offset.y() - dir.y() * offset.x(), before + along))
+    }
+}
+
+//A way of getting a bullet to a point, time is the flight time until it gets there
+#[derive(Debug, Copy, Clone)]
+pub struct BankShot {
+    pub angle: f32,
+    pub bounces: usize,
+    pub time: f32,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct RayHit {
+    pub pos: Vec2,
+    pub dist: f32,
+    pub normal: Vec2,
 }
 
 #[derive(Debug, Copy, Clone, Serialize, Deserialize)]
@@ -91,6 +244,26 @@ impl Shape {
         }
     }
 
+    //Axis aligned bounding box as (min, max)
+    pub fn bounds(&self) -> (Vec2, Vec2) {
+        match &self {
+            Shape::Rect { pos, w_h } => {
+                (*pos - *w_h, *pos + *w_h)
+            }
+            Shape::RotRect { pos, w_h, rot } => {
+                let (sin, cos) = rot.sin_cos();
+                let extent = Vec2::new(
+                    cos.abs() * w_h.x() + sin.abs() * w_h.y(),
+                    sin.abs() * w_h.x() + cos.abs() * w_h.y(),
+                );
+                (*pos - extent, *pos + extent)
+            }
+            Shape::Circle { pos, r } => {
+                (*pos - *r, *pos + *r)
+            }
+        }
+    }
+
     pub fn intersects(&self, mut test: Vec2) -> bool {
         match &self {
             Shape::Rect { pos, w_h } => {
@@ -118,23 +291,22 @@ impl Shape {
     }
 
 
-    //TODO: fix w_h
-    pub fn surface_angle(&self, mut test: Vec2) -> f32 {
+    //Outward normals of the faces test lies on, the second one is only set near a corner
+    pub fn surface_normals(&self, mut test: Vec2) -> (Vec2, Option<Vec2>) {
         match &self {
-            Shape::Rect { pos, .. } => {
-                let diff = (test - *pos);
-                if diff.y().abs() < diff.x().abs() {
-                    if diff.x() > 0f32 {
-                        0f32
-                    } else {
-                        std::f32::consts::PI
-                    }
+            Shape::Rect { pos, w_h } => {
+                let diff = test - *pos;
+                let x_face = Vec2::new(diff.x().signum(), 0f32);
+                let y_face = Vec2::new(0f32, diff.y().signum());
+                //How far inside each pair of faces the point is, negative when outside
+                let x_gap = w_h.x() - diff.x().abs();
+                let y_gap = w_h.y() - diff.y().abs();
+                if x_gap.abs() < CORNER_TOLERANCE && y_gap.abs() < CORNER_TOLERANCE {
+                    (x_face, Some(y_face))
+                } else if x_gap < y_gap {
+                    (x_face, None)
                 } else {
-                    if diff.y() > 0f32 {
-                        -std::f32::consts::PI * 0.5f32
-                    } else {
-                        std::f32::consts::PI * 0.5f32
-                    }
+                    (y_face, None)
                 }
             }
             Shape::RotRect { pos, w_h, rot } => {
@@ -142,16 +314,109 @@ impl Shape {
                 test -= center;
                 test.rotate(Vec2::from_angle(-*rot));
                 test += center;
-                Rect {
+                let (mut first, second) = Rect {
                     pos: *pos,
                     w_h: *w_h,
-                }.surface_angle(test) + *rot
+                }.surface_normals(test);
+                first.rotate(Vec2::from_angle(*rot));
+                (first, second.map(|mut normal| {
+                    normal.rotate(Vec2::from_angle(*rot));
+                    normal
+                }))
             }
             Shape::Circle { pos, .. } => {
-                (test - *pos).angle()
+                let mut normal = test - *pos;
+                normal.normalize();
+                (normal, None)
             }
         }
     }
+
+    //Angle of the outward normal at test, corners use the diagonal between both faces
+    pub fn surface_angle(&self, test: Vec2) -> f32 {
+        match self.surface_normals(test) {
+            (first, Some(second)) => (first + second).angle(),
+            (first, None) => first.angle()
+        }
+    }
+
+    //Distances along the ray (dir must be normalized) where it enters and leaves the shape,
+    //together with the normal of the face it enters through
+    pub fn ray_interval(&self, origin: Vec2, dir: Vec2) -> Option<(f32, f32, Vec2)> {
+        match &self {
+            Shape::Rect { pos, w_h } => {
+                let min = *pos - *w_h;
+                let max = *pos + *w_h;
+                let mut t_in = f32::NEG_INFINITY;
+                let mut t_out = f32::INFINITY;
+                let mut normal = Vec2::new(0f32, 0f32);
+                for &(o, d, lo, hi, axis) in &[
+                    (origin.x(), dir.x(), min.x(), max.x(), Vec2::new(1f32, 0f32)),
+                    (origin.y(), dir.y(), min.y(), max.y(), Vec2::new(0f32, 1f32)),
+                ] {
+                    if d == 0f32 {
+                        if o <= lo || o >= hi {
+                            return None;
+                        }
+                        continue;
+                    }
+                    let t_lo = (lo - o) / d;
+                    let t_hi = (hi - o) / d;
+                    let (near, far) = if t_lo < t_hi { (t_lo, t_hi) } else { (t_hi, t_lo) };
+                    if near > t_in {
+                        t_in = near;
+                        normal = axis * -d.signum();
+                    }
+                    t_out = t_out.min(far);
+                }
+                if t_in <= t_out {
+                    Some((t_in, t_out, normal))
+                } else {
+                    None
+                }
+            }
+            Shape::RotRect { pos, w_h, rot } => {
+                let mut local_origin = origin - *pos;
+                local_origin.rotate(Vec2::from_angle(-*rot));
+                let mut local_dir = dir;
+                local_dir.rotate(Vec2::from_angle(-*rot));
+                Rect {
+                    pos: *pos,
+                    w_h: *w_h,
+                }.ray_interval(local_origin + *pos, local_dir).map(|(t_in, t_out, mut normal)| {
+                    normal.rotate(Vec2::from_angle(*rot));
+                    (t_in, t_out, normal)
+                })
+            }
+            Shape::Circle { pos, r } => {
+                let offset = origin - *pos;
+                let b = offset.x() * dir.x() + offset.y() * dir.y();
+                let c = offset.sq_magnitude() - r * r;
+                let disc = b * b - c;
+                if disc < 0f32 {
+                    return None;
+                }
+                let root = disc.sqrt();
+                let t_in = -b - root;
+                Some((t_in, -b + root, (offset + dir * t_in) / *r))
+            }
+        }
+    }
+
+    //First point at a distance in [min_dist, max_dist] along the ray that lies in the shape.
+    //A ray that starts inside the shape hits at min_dist.
+    pub fn ray_hit(&self, origin: Vec2, dir: Vec2, min_dist: f32, max_dist: f32) -> Option<RayHit> {
+        let (t_in, t_out, normal) = self.ray_interval(origin, dir)?;
+        if t_out < min_dist || t_in > max_dist {
+            return None;
+        }
+        let dist = t_in.max(min_dist);
+        Some(RayHit {
+            pos: origin + dir * dist,
+            dist,
+            normal,
+        })
+    }
 }
 
 const CONTAINER_SIDE: Vec2 = Vec2::new(63.5487 * 2.05, 31.6329 * 2.05);
@@ -346,9 +611,7 @@ impl Map {
             "./maps/MapA.json",
             serde_json::to_string(&shapes).unwrap(),
         );
-        Map {
-            shapes: Arc::new(serde_json::from_str(&std::fs::read_to_string("./maps/MapA.json").unwrap()).unwrap())
-        }
+        Map::new(serde_json::from_str(&std::fs::read_to_string("./maps/MapA.json").unwrap()).unwrap())
     }
 
     pub fn new_map_b() -> Self {
@@ -721,9 +984,7 @@ impl Map {
 
 
         std::fs::write("./maps/MapB.json", serde_json::to_string(&shapes).unwrap());
-        Map {
-            shapes: Arc::new(serde_json::from_str(&std::fs::read_to_string("./maps/MapB.json").unwrap()).unwrap()),
-        }
+        Map::new(serde_json::from_str(&std::fs::read_to_string("./maps/MapB.json").unwrap()).unwrap())
     }
 
     pub fn new_map_c() -> Self {
@@ -916,72 +1177,169 @@ impl Map {
             }];
 
         std::fs::write("./maps/MapC.json", serde_json::to_string(&shapes).unwrap());
+        Map::new(serde_json::from_str(&std::fs::read_to_string("./maps/MapC.json").unwrap()).unwrap())
+    }
+
+    pub fn new(shapes: Vec<Shape>) -> Self {
+        let grid = Grid::new(&shapes, GRID_CELL);
         Map {
-            shapes: Arc::new(serde_json::from_str(&std::fs::read_to_string("./maps/MapC.json").unwrap()).unwrap()),
+            shapes: Arc::new(shapes),
+            grid: Arc::new(grid),
+            rules: GameRules::default(),
         }
     }
 
-    pub fn map_from_file(path: &str) -> Self {
+    //The same shapes played by other rules
+    pub fn with_rules(&self, rules: GameRules) -> Self {
         Map {
-            shapes: Arc::new(serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap()),
+            rules,
+            ..self.clone()
         }
     }
 
+    pub fn rules(&self) -> &GameRules {
+        &self.rules
+    }
+
+    pub fn map_from_file(path: &str) -> Self {
+        Map::new(serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap())
+    }
+
     pub fn intersects(&self, point: Vec2) -> bool {
-        for shape in self.shapes.iter() {
-            if shape.intersects(point) {
-                return true;
-            }
+        self.grid
+            .candidates(point)
+            .iter()
+            .any(|&index| self.shapes[index].intersects(point))
+    }
+
+    //Whether a tank at pos turned by rot overlaps a wall or sticks out of the arena. The whole
+    //hull counts, the same one bullets hit.
+    pub fn blocks_tank(&self, pos: Vec2, rot: f32) -> bool {
+        let (hull, corners) = self.tank_hull(pos, rot);
+        let arena = self.rules.arena;
+        if corners.iter().any(|c| c.x() < 0f32 || c.y() < 0f32 || c.x() > arena.x() || c.y() > arena.y()) {
+            return true;
         }
-        false
+        let (lo, hi) = hull.bounds();
+        self.grid
+            .overlapping(lo, hi)
+            .iter()
+            .any(|&index| Self::touches_hull(&self.shapes[index], pos, &hull, &corners))
+    }
+
+    fn tank_hull(&self, pos: Vec2, rot: f32) -> (Shape, [Vec2; 4]) {
+        let size = self.rules.hull_extents();
+        let mut corners = [Vec2::new(size.x(), size.y()), Vec2::new(size.x(), -size.y()),
+            Vec2::new(-size.x(), -size.y()), Vec2::new(-size.x(), size.y())];
+        for corner in corners.iter_mut() {
+            corner.rotate(Vec2::from_angle(rot));
+            *corner += pos;
+        }
+        (Shape::RotRect {
+            pos,
+            w_h: size,
+            rot,
+        }, corners)
+    }
+
+    //hull is centered on pos with the given corners
+    fn touches_hull(shape: &Shape, pos: Vec2, hull: &Shape, corners: &[Vec2; 4]) -> bool {
+        let (lo, hi) = hull.bounds();
+        let (shape_lo, shape_hi) = shape.bounds();
+        if shape_hi.x() < lo.x() || shape_hi.y() < lo.y() || shape_lo.x() > hi.x() || shape_lo.y() > hi.y() {
+            return false;
+        }
+        //Either one holds the other's center or an edge of the hull runs through the shape
+        if shape.intersects(pos) || hull.intersects((shape_lo + shape_hi) * 0.5f32) {
+            return true;
+        }
+        (0..corners.len()).any(|index| {
+            let from = corners[index];
+            let edge = corners[(index + 1) % corners.len()] - from;
+            let length = edge.sq_magnitude().sqrt();
+            shape.ray_interval(from, edge / length)
+                .map_or(false, |(t_in, t_out, _)| t_in <= length && t_out >= 0f32)
+        })
     }
 
     pub fn ray_cast(&self, source: Vec2, angles: &[f32], step_size: f32, steps: Range<usize>) -> Vec<(Option<Shape>, Vec2, usize)> {
         let thread_cnt = angles.len().min(4);
-        Self::para_cast(source, angles, step_size, steps, self.shapes.clone(), thread_cnt)
+        self.para_cast(source, angles, step_size, steps, thread_cnt)
     }
 
-    //TODO: single calculation step
-    pub fn single_cast(source: Vec2, angle: f32, step_size: f32, steps: Range<usize>, shapes: Arc<Vec<Shape>>) -> (Option<Shape>, Vec2, usize) {
-        let step = Vec2::from_angle(angle) * step_size;
-        let end = steps.end;
+    pub fn single_cast(&self, source: Vec2, angle: f32, step_size: f32, steps: Range<usize>) -> (Option<Shape>, Vec2, usize) {
+        let dir = Vec2::from_angle(angle);
+        let last = steps.end.saturating_sub(1).max(steps.start);
+        match self.cast(
+            source,
+            dir,
+            steps.start as f32 * step_size,
+            last as f32 * step_size,
+        ) {
+            Some((shape, hit)) => (Some(shape), hit.pos, (hit.dist / step_size).ceil() as usize),
+            None => (None, source + dir * (last as f32 * step_size), steps.end)
+        }
+    }
 
-        let mut test = source;
-        for s in steps {
-            test = source + step * (s as f32);
-            for shape in shapes.iter() {
-                if shape.intersects(test) {
-                    return (Some(*shape), test, s);
+    //Closest shape the ray hits between min_dist and max_dist
+    pub fn cast(&self, source: Vec2, dir: Vec2, min_dist: f32, max_dist: f32) -> Option<(Shape, RayHit)> {
+        let mut closest: Option<(Shape, RayHit)> = None;
+        self.grid.traverse(source, dir, min_dist, max_dist, |candidates, exit| {
+            for &index in candidates {
+                let shape = self.shapes[index];
+                let limit = closest.map_or(max_dist, |(_, hit)| hit.dist);
+                if let Some(hit) = shape.ray_hit(source, dir, min_dist, limit) {
+                    if closest.map_or(true, |(_, best)| hit.dist < best.dist) {
+                        closest = Some((shape, hit));
+                    }
                 }
             }
-        }
-        (None, test, end)
+            closest.map_or(false, |(_, hit)| hit.dist <= exit)
+        });
+        closest
+    }
+
+    pub fn reflect(angle: f32, normal: Vec2) -> f32 {
+        let dir = Vec2::from_angle(angle);
+        let along = dir.x() * normal.x() + dir.y() * normal.y();
+        (dir - normal * (2f32 * along)).angle()
     }
 
     pub fn get_bounce(pos: Vec2, angle: f32, shape: Shape) -> f32 {
-        let s_angle = shape.surface_angle(pos);
-        let mut angle = angle + std::f32::consts::PI;
-        2f32 * s_angle - angle
+        let dir = Vec2::from_angle(angle);
+        let facing = |normal: Vec2| dir.x() * normal.x() + dir.y() * normal.y() < 0f32;
+        let normal = match shape.surface_normals(pos) {
+            //A ray grazing along one face past the corner only bounces off the face it hits
+            (first, Some(second)) if facing(first) != facing(second) => {
+                if facing(first) { first } else { second }
+            }
+            (first, Some(second)) => {
+                let mut normal = first + second;
+                normal.normalize();
+                normal
+            }
+            (first, None) => first
+        };
+        Self::reflect(angle, normal)
     }
 
-    fn para_cast(source: Vec2, angles: &[f32], step_size: f32, steps: Range<usize>, shapes: Arc<Vec<Shape>>, threads: usize) -> Vec<(Option<Shape>, Vec2, usize)> {
+    fn para_cast(&self, source: Vec2, angles: &[f32], step_size: f32, steps: Range<usize>, threads: usize) -> Vec<(Option<Shape>, Vec2, usize)> {
         let mut out = vec![];
         let chunks = angles.len() / threads + 1;
         let mut handles = vec![];
         for angle in angles.chunks(chunks) {
             let angles = angle.to_vec();
             let steps_clone = steps.clone();
-            let shapes_clone = shapes.clone();
+            let map = self.clone();
             handles.push(std::thread::spawn(move || {
                 let mut dists = vec![];
                 for angle in angles {
                     dists.push(
-                        Self::single_cast(
+                        map.single_cast(
                             source,
                             angle,
                             step_size,
                             steps_clone.clone(),
-                            shapes_clone.clone(),
                         )
                     )
                 }
@@ -995,27 +1353,462 @@ impl Map {
         out
     }
 
-    pub fn get_bullet_trajectory(&self, bullet: Bullet, step_size: f32, max_bounces: usize) -> BulletTrajectory {
+    pub fn get_bullet_trajectory(&self, bullet: Bullet, max_bounces: usize) -> BulletTrajectory {
         let mut source = bullet.pos();
         let mut angle = bullet.vel().angle();
         let mut bounces = vec![source];
+        //A bullet keeps the speed it was reported with, one standing still is taken to be just fired
+        let speed = match bullet.vel().sq_magnitude().sqrt() {
+            speed if speed > 0f32 => speed,
+            _ => self.rules.bullet_speed,
+        };
 
-        let mut step_cnt = (MAX_DIST / step_size).ceil() as usize;
-
+        let mut remaining = speed * self.rules.bullet_lifetime;
         for _ in 0..max_bounces {
-            let (shape, pos, steps) = Self::single_cast(source, angle, step_size, 10..step_cnt, self.shapes.clone());
-            if steps >= step_cnt {
-                break;
+            let dir = Vec2::from_angle(angle);
+            match self.cast(source, dir, BOUNCE_CLEARANCE, remaining) {
+                Some((shape, hit)) => {
+                    remaining -= hit.dist;
+                    bounces.push(hit.pos);
+                    source = hit.pos;
+                    angle = Self::get_bounce(hit.pos, angle, shape);
+                }
+                None => {
+                    bounces.push(source + dir * remaining);
+                    break;
+                }
+            }
+        }
+        BulletTrajectory::new(bounces, speed)
+    }
+
+    //When a bullet fired from source along angle comes back into the shooter, whose hull at a
+    //time after firing is given by hull
+    pub fn self_hit<F: Fn(f32) -> Shape>(&self, source: Vec2, angle: f32, max_bounces: usize, hull: F, until: f32) -> Option<f32> {
+        self.get_bullet_trajectory(Bullet::new_v(source, Vec2::from_angle(angle) * self.rules.bullet_speed), max_bounces)
+            .returns_to(hull, until)
+    }
+
+    //Every angle that sends a bullet from source through target with at most max_bounces bounces
+    //on the way, quickest first. Found where the side of the path the target is on flips between
+    //two neighbouring angles, flips across a corner are jumps and get dropped.
+    pub fn bank_shots(&self, source: Vec2, target: Vec2, max_bounces: usize) -> Vec<BankShot> {
+        let step = std::f32::consts::PI * 2f32 / BANK_SWEEP as f32;
+        //One bounce more than needed so the last leg ends where the bullet hits
+        let trajectory = |angle: f32| self.get_bullet_trajectory(
+            Bullet::new_v(source, Vec2::from_angle(angle) * self.rules.bullet_speed),
+            max_bounces + 1,
+        );
+        let sweep = (0..=BANK_SWEEP)
+            .map(|index| trajectory(index as f32 * step))
+            .collect::<Vec<_>>();
+        let mut shots: Vec<BankShot> = vec![];
+        for leg in 0..=max_bounces {
+            for index in 0..BANK_SWEEP {
+                let (mut low_side, high_side) = match (sweep[index].passes(target, leg), sweep[index + 1].passes(target, leg)) {
+                    (Some((low, _)), Some((high, _))) => (low, high),
+                    _ => continue
+                };
+                if low_side != 0f32 && low_side.signum() == high_side.signum() {
+                    continue;
+                }
+                let (mut low, mut high) = (index as f32 * step, (index + 1) as f32 * step);
+                for _ in 0..BANK_REFINE {
+                    let mid = (low + high) * 0.5f32;
+                    match trajectory(mid).passes(target, leg) {
+                        Some((side, _)) if side.signum() == low_side.signum() => {
+                            low = mid;
+                            low_side = side;
+                        }
+                        Some(_) => high = mid,
+                        None => break
+                    }
+                }
+                let angle = (low + high) * 0.5f32;
+                let path = trajectory(angle);
+                let dist = match path.passes(target, leg) {
+                    Some((side, dist)) if side.abs() <= BANK_TOLERANCE => dist,
+                    _ => continue
+                };
+                //Already there before this leg
+                let earlier = (0..leg).any(|leg| path
+                    .passes(target, leg)
+                    .map_or(false, |(side, _)| side.abs() <= BANK_TOLERANCE));
+                let seen = shots.iter().any(|shot| {
+                    let apart = (shot.angle - angle).abs();
+                    shot.bounces == leg && apart.min(std::f32::consts::PI * 2f32 - apart) < step
+                });
+                if earlier || seen {
+                    continue;
+                }
+                shots.push(BankShot {
+                    angle,
+                    bounces: leg,
+                    time: dist / self.rules.bullet_speed,
+                });
+            }
+        }
+        shots.sort_by(|a, b| a.time.partial_cmp(&b.time).unwrap_or(Ordering::Equal));
+        shots
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAPS: [&str; 3] = ["./maps/MapA.json", "./maps/MapB.json", "./maps/MapC.json"];
+
+    //The 1 px ray marcher the analytic cast replaced
+    fn march(source: Vec2, angle: f32, steps: Range<usize>, shapes: &[Shape]) -> Option<(Vec2, usize)> {
+        let step = Vec2::from_angle(angle);
+        for s in steps {
+            let test = source + step * (s as f32);
+            if shapes.iter().any(|shape| shape.intersects(test)) {
+                return Some((test, s));
+            }
+        }
+        None
+    }
+
+    fn open_points(map: &Map) -> Vec<Vec2> {
+        let mut points = vec![];
+        for x in 1..13 {
+            for y in 1..7 {
+                let point = Vec2::new(x as f32 * 100f32, y as f32 * 100f32 + 10f32);
+                if !map.intersects(point) {
+                    points.push(point);
+                }
+            }
+        }
+        points
+    }
+
+    fn rules() -> GameRules {
+        GameRules::default()
+    }
+
+    fn dist(a: Vec2, b: Vec2) -> f32 {
+        (a - b).sq_magnitude().sqrt()
+    }
+
+    #[test]
+    fn ray_hits_each_shape_kind() {
+        let origin = Vec2::new(-20f32, 0f32);
+        let dir = Vec2::new(1f32, 0f32);
+        let rect = Rect { pos: Vec2::new(0f32, 0f32), w_h: Vec2::new(10f32, 5f32) };
+        let rot_rect = RotRect { pos: Vec2::new(0f32, 0f32), w_h: Vec2::new(10f32, 5f32), rot: std::f32::consts::FRAC_PI_2 };
+        let circle = Circle { pos: Vec2::new(0f32, 0f32), r: 5f32 };
+        for (shape, expected) in &[(rect, 10f32), (rot_rect, 15f32), (circle, 15f32)] {
+            let hit = shape.ray_hit(origin, dir, 0f32, 100f32).unwrap();
+            assert!((hit.dist - expected).abs() < 1e-4, "{:?} {:?}", shape, hit);
+            assert!(dist(hit.pos, Vec2::new(expected - 20f32, 0f32)) < 1e-4);
+            assert!(dist(hit.normal, Vec2::new(-1f32, 0f32)) < 1e-4);
+        }
+        assert!(rect.ray_hit(origin, Vec2::new(0f32, 1f32), 0f32, 100f32).is_none());
+        assert!(rect.ray_hit(origin, dir, 0f32, 5f32).is_none());
+    }
+
+    //Both casts agree, or the analytic one clipped a sliver thinner than a marcher step
+    fn agrees(map: &Map, source: Vec2, angle: f32, min_dist: f32, max_dist: f32) -> bool {
+        let dir = Vec2::from_angle(angle);
+        let marched = march(source, angle, min_dist as usize..max_dist as usize + 1, &map.shapes);
+        let cast = map.cast(source, dir, min_dist, max_dist);
+        let sliver = |shape: Shape| {
+            let (t_in, t_out, _) = shape.ray_interval(source, dir).unwrap();
+            t_out - t_in < 1.5f32
+        };
+        match (marched, cast) {
+            (Some((pos, steps)), Some((shape, hit))) => {
+                hit.dist <= steps as f32 + 1e-2 && (dist(pos, hit.pos) < 1.5f32 || sliver(shape))
+            }
+            (None, Some((shape, _))) => sliver(shape),
+            (Some(_), None) => false,
+            (None, None) => true
+        }
+    }
+
+    #[test]
+    fn cast_matches_marcher() {
+        for path in &MAPS {
+            let map = Map::map_from_file(path);
+            for source in open_points(&map) {
+                for i in 0..48 {
+                    let angle = i as f32 / 48f32 * std::f32::consts::PI * 2f32;
+                    assert!(agrees(&map, source, angle, 0f32, 2399f32), "{} {:?} {}", path, source, angle);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn trajectory_first_bounce_matches_cast() {
+        for path in &MAPS {
+            let map = Map::map_from_file(path);
+            for source in open_points(&map) {
+                for i in 0..16 {
+                    let angle = i as f32 / 16f32 * std::f32::consts::PI * 2f32;
+                    let dir = Vec2::from_angle(angle);
+                    let trajectory = map.get_bullet_trajectory(Bullet::new_v(source, dir * rules().bullet_speed), 1);
+                    let bounce = trajectory.bounces()[1];
+                    match map.cast(source, dir, BOUNCE_CLEARANCE, rules().max_dist()) {
+                        Some((_, hit)) => assert!(dist(hit.pos, bounce) < 1e-3),
+                        None => assert!((dist(source, bounce) - rules().max_dist()).abs() < 1e-1)
+                    }
+                    assert!(agrees(&map, source, angle, 10f32, rules().max_dist()));
+                }
             }
-            step_cnt -= steps;
-            bounces.push(pos);
-            if let Some(shape) = shape {
-                source = pos;
-                angle = Self::get_bounce(pos, angle, shape);
-            } else {
-                return BulletTrajectory::new(bounces);
+        }
+    }
+
+    #[test]
+    fn long_face_of_rect() {
+        let rect = Rect { pos: Vec2::new(0f32, 0f32), w_h: Vec2::new(130f32, 65f32) };
+        let angle = rect.surface_angle(Vec2::new(100f32, 64.9f32));
+        assert!((angle - std::f32::consts::FRAC_PI_2).abs() < 1e-4);
+        let rot_rect = RotRect { pos: Vec2::new(0f32, 0f32), w_h: Vec2::new(130f32, 65f32), rot: std::f32::consts::FRAC_PI_2 };
+        let angle = rot_rect.surface_angle(Vec2::new(-64.9f32, 100f32));
+        assert!((angle.abs() - std::f32::consts::PI).abs() < 1e-4);
+        let bounce = Map::get_bounce(Vec2::new(100f32, 65f32), -std::f32::consts::FRAC_PI_4, rect);
+        assert!((bounce - std::f32::consts::FRAC_PI_4).abs() < 1e-4);
+    }
+
+    #[test]
+    fn corners_need_both_faces_close() {
+        let rect = Rect { pos: Vec2::new(0f32, 0f32), w_h: Vec2::new(100f32, 100f32) };
+        assert!(rect.surface_normals(Vec2::new(99.8f32, 99.9f32)).1.is_some());
+        //As far from both faces, but nowhere near the corner
+        assert!(rect.surface_normals(Vec2::new(50f32, 50f32)).1.is_none());
+        assert!(rect.surface_normals(Vec2::new(100f32, 50f32)).1.is_none());
+    }
+
+    fn shrink(shape: Shape, by: f32) -> Shape {
+        match shape {
+            Rect { pos, w_h } => Rect { pos, w_h: w_h - by },
+            RotRect { pos, w_h, rot } => RotRect { pos, w_h: w_h - by, rot },
+            Circle { pos, r } => Circle { pos, r: r - by }
+        }
+    }
+
+    //Face centres, points along the faces and the exact corners
+    fn targets(shape: Shape) -> Vec<Vec2> {
+        let mut targets = vec![];
+        let fractions = [-1f32, -0.75f32, -0.5f32, 0f32, 0.5f32, 0.75f32, 1f32];
+        match shape {
+            Rect { pos, w_h } | RotRect { pos, w_h, .. } => {
+                let rot = if let RotRect { rot, .. } = shape { rot } else { 0f32 };
+                for &fx in &fractions {
+                    for &fy in &fractions {
+                        let mut offset = Vec2::new(fx * w_h.x(), fy * w_h.y());
+                        offset.rotate(Vec2::from_angle(rot));
+                        targets.push(pos + offset);
+                    }
+                }
+            }
+            Circle { pos, r } => {
+                for i in 0..16 {
+                    targets.push(pos + Vec2::from_angle(i as f32 / 16f32 * std::f32::consts::PI * 2f32) * r * 0.9f32);
+                }
+            }
+        }
+        targets
+    }
+
+    #[test]
+    fn bounces_leave_every_shape() {
+        for path in &MAPS {
+            let map = Map::map_from_file(path);
+            for &shape in map.shapes.iter() {
+                let (lo, hi) = shape.bounds();
+                let center = (lo + hi) * 0.5f32;
+                let radius = dist(lo, hi) + 50f32;
+                let inner = shrink(shape, 1e-2);
+                for i in 0..36 {
+                    let source = center + Vec2::from_angle(i as f32 / 36f32 * std::f32::consts::PI * 2f32) * radius;
+                    for target in targets(shape) {
+                        let mut dir = target - source;
+                        dir.normalize();
+                        let hit = match shape.ray_hit(source, dir, 0f32, f32::INFINITY) {
+                            Some(hit) => hit,
+                            None => continue
+                        };
+                        let bounce = Vec2::from_angle(Map::get_bounce(hit.pos, dir.angle(), shape));
+                        for step in 1..100 {
+                            let point = hit.pos + bounce * (step as f32 * 0.5f32);
+                            assert!(!inner.intersects(point), "{} {:?} from {:?} to {:?}", path, shape, source, target);
+                        }
+                    }
+                }
             }
         }
-        BulletTrajectory::new(bounces)
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn bank_shot_off_a_floor() {
+        let floor = Rect { pos: Vec2::new(640f32, 800f32), w_h: Vec2::new(1000f32, 100f32) };
+        let map = Map::new(vec![floor]);
+        let source = Vec2::new(200f32, 400f32);
+        let target = Vec2::new(800f32, 400f32);
+        let shots = map.bank_shots(source, target, 2);
+        assert_eq!(shots.len(), 2, "{:?}", shots);
+        assert_eq!(shots[0].bounces, 0);
+        assert!(shots[0].angle.abs() < 1e-3 || (shots[0].angle - std::f32::consts::PI * 2f32).abs() < 1e-3);
+        assert!((shots[0].time - 1.5f32).abs() < 1e-3);
+        //Aimed at the target mirrored in the floor
+        let mirrored = Vec2::new(800f32, 1000f32);
+        assert_eq!(shots[1].bounces, 1);
+        assert!((shots[1].angle - (mirrored - source).angle()).abs() < 1e-3);
+        assert!((shots[1].time - dist(source, mirrored) / rules().bullet_speed).abs() < 1e-3);
+    }
+
+    #[test]
+    fn bank_shots_reach_the_target() {
+        let mut bounced = 0;
+        for path in &MAPS {
+            let map = Map::map_from_file(path);
+            let points = open_points(&map);
+            for (&source, &target) in points.iter().zip(points.iter().rev()).take(8) {
+                let shots = map.bank_shots(source, target, 2);
+                for pair in shots.windows(2) {
+                    assert!(pair[0].time <= pair[1].time);
+                }
+                for shot in shots {
+                    assert!(shot.bounces <= 2);
+                    bounced += shot.bounces.min(1);
+                    let trajectory = map.get_bullet_trajectory(Bullet::new_v(source, Vec2::from_angle(shot.angle) * rules().bullet_speed), 3);
+                    let step = 0.25f32;
+                    let (closest, bounces) = trajectory
+                        .sample(step, (shot.time * rules().bullet_speed / step) as usize + 8)
+                        .into_iter()
+                        .flatten()
+                        .map(|(pos, bounces)| (dist(pos, target), bounces))
+                        .fold((f32::INFINITY, 0), |best, next| if next.0 < best.0 { next } else { best });
+                    assert!(closest < 1f32, "{} {:?} to {:?}: {:?} misses by {}", path, source, target, shot, closest);
+                    assert_eq!(bounces, shot.bounces);
+                }
+            }
+        }
+        assert!(bounced > 0);
+    }
+
+    #[test]
+    fn own_bullet_comes_back() {
+        let wall = Rect { pos: Vec2::new(600f32, 360f32), w_h: Vec2::new(20f32, 300f32) };
+        let map = Map::new(vec![wall]);
+        let source = Vec2::new(200f32, 360f32);
+        let hull = |_: f32| RotRect { pos: source, w_h: Vec2::new(80f32, 120f32), rot: 0f32 };
+        //Out to the wall at 580 and back to the front of the hull at 280
+        let time = map.self_hit(source, 0f32, 10, hull, 3f32).unwrap();
+        assert!((time - 680f32 / rules().bullet_speed).abs() < 0.02f32, "{}", time);
+        assert!(map.self_hit(source, 0f32, 10, hull, 1f32).is_none());
+        assert!(map.self_hit(source, std::f32::consts::PI, 10, hull, 3f32).is_none());
+        //Gone by the time it gets back
+        let moved = |time: f32| RotRect { pos: source + Vec2::new(0f32, time * 150f32), w_h: Vec2::new(80f32, 120f32), rot: 0f32 };
+        assert!(map.self_hit(source, 0f32, 10, moved, 3f32).is_none());
+        //Enemy bullets count as soon as they touch
+        let trajectory = map.get_bullet_trajectory(Bullet::new_v(source, Vec2::new(rules().bullet_speed, 0f32)), 10);
+        assert_eq!(trajectory.strikes(hull, 3f32), Some(STRIKE_STEP));
+    }
+
+    fn l_shape() -> BulletTrajectory {
+        BulletTrajectory::new(vec![Vec2::new(0f32, 0f32), Vec2::new(400f32, 0f32), Vec2::new(400f32, 800f32)], 400f32)
+    }
+
+    #[test]
+    fn trajectory_timing() {
+        let trajectory = l_shape();
+        assert_eq!(trajectory.lifetime(), 3f32);
+        assert!(dist(trajectory.position_in(0f32).unwrap(), Vec2::new(0f32, 0f32)) < 1e-4);
+        assert!(dist(trajectory.position_in(0.5f32).unwrap(), Vec2::new(200f32, 0f32)) < 1e-4);
+        assert!(dist(trajectory.position_in(1f32).unwrap(), Vec2::new(400f32, 0f32)) < 1e-4);
+        assert!(dist(trajectory.position_in(2f32).unwrap(), Vec2::new(400f32, 400f32)) < 1e-4);
+        assert!(dist(trajectory.position_in(3f32).unwrap(), Vec2::new(400f32, 800f32)) < 1e-4);
+        assert!(trajectory.position_in(3.01f32).is_none());
+        assert!(trajectory.position_in(-0.01f32).is_none());
+    }
+
+    #[test]
+    fn trajectory_segments() {
+        let trajectory = l_shape();
+        assert!(dist(trajectory.velocity(0).unwrap(), Vec2::new(rules().bullet_speed, 0f32)) < 1e-4);
+        assert!(dist(trajectory.velocity(1).unwrap(), Vec2::new(0f32, rules().bullet_speed)) < 1e-4);
+        assert!(trajectory.velocity(2).is_none());
+        assert!(dist(trajectory.velocity_in(2.5f32).unwrap(), Vec2::new(0f32, rules().bullet_speed)) < 1e-4);
+        assert!(trajectory.velocity_in(4f32).is_none());
+        assert_eq!(trajectory.bounces_at(0f32), 0);
+        assert_eq!(trajectory.bounces_at(0.99f32), 0);
+        assert_eq!(trajectory.bounces_at(1f32), 1);
+        assert_eq!(trajectory.bounces_at(3f32), 1);
+        assert_eq!(trajectory.bounces_at(5f32), 1);
+    }
+
+    #[test]
+    fn trajectory_time_to_reach() {
+        let trajectory = l_shape();
+        assert_eq!(trajectory.time_to_reach(Vec2::new(200f32, 0f32)), Some(0.5f32));
+        assert_eq!(trajectory.time_to_reach(Vec2::new(400f32, 200f32)), Some(1.5f32));
+        assert_eq!(trajectory.time_to_reach(Vec2::new(400f32, 800f32)), Some(3f32));
+        assert!(trajectory.time_to_reach(Vec2::new(100f32, 100f32)).is_none());
+        assert!(trajectory.time_to_reach(Vec2::new(400f32, 801f32)).is_none());
+        //Crossing its own path is reached the first time round
+        let crossing = BulletTrajectory::new(vec![
+            Vec2::new(0f32, 0f32), Vec2::new(800f32, 0f32), Vec2::new(400f32, 400f32), Vec2::new(400f32, -400f32),
+        ], 400f32);
+        assert_eq!(crossing.time_to_reach(Vec2::new(400f32, 0f32)), Some(1f32));
+    }
+
+    #[test]
+    fn trajectory_follows_the_bounces() {
+        let map = Map::map_from_file(MAPS[1]);
+        let vel = Vec2::new(300f32, 264.6f32);
+        for source in open_points(&map) {
+            let trajectory = map.get_bullet_trajectory(Bullet::new_v(source, vel), 10);
+            let path = trajectory.bounces();
+            for (index, pair) in path.windows(2).enumerate() {
+                let time = trajectory.time_to_reach(pair[1]).unwrap();
+                assert!(dist(trajectory.position_in(time).unwrap(), pair[1]) < REACH_TOLERANCE + 1e-2);
+                assert!(trajectory.bounces_at(time) <= index + 1);
+                let speed = trajectory.velocity(index).unwrap().sq_magnitude().sqrt();
+                assert!((speed - vel.sq_magnitude().sqrt()).abs() < 1e-2);
+            }
+            //Out of range, or stopped at the last bounce allowed
+            assert!((trajectory.lifetime() * rules().bullet_speed - rules().max_dist()).abs() < 1e-1 || path.len() == 11);
+        }
+    }
+
+    #[test]
+    fn trajectory_keeps_the_bullets_speed() {
+        let map = Map::map_from_file(MAPS[1]);
+        for source in open_points(&map) {
+            let trajectory = map.get_bullet_trajectory(Bullet::new_v(source, Vec2::new(480f32, 360f32)), 10);
+            for index in 0..trajectory.bounces().len() - 1 {
+                let speed = trajectory.velocity(index).unwrap().sq_magnitude().sqrt();
+                assert!((speed - 600f32).abs() < 1e-2);
+            }
+            assert!((trajectory.lifetime() - rules().bullet_lifetime).abs() < 1e-3 || trajectory.bounces().len() == 11);
+        }
+        let source = Vec2::new(100f32, 100f32);
+        let fast = Map::new(vec![]).get_bullet_trajectory(Bullet::new_v(source, Vec2::new(600f32, 0f32)), 10);
+        assert_eq!(fast.time_to_reach(Vec2::new(400f32, 100f32)), Some(0.5f32));
+        //One standing still has only just been fired
+        let fired = Map::new(vec![]).get_bullet_trajectory(Bullet::new_v(source, Vec2::new(0f32, 0f32)), 10);
+        assert_eq!(fired.time_to_reach(Vec2::new(500f32, 100f32)), Some(1f32));
+    }
+
+    #[test]
+    fn blocks_tank_checks_every_shape_it_could_touch() {
+        for path in &MAPS {
+            let map = Map::map_from_file(path);
+            for x in 0..32 {
+                for y in 0..18 {
+                    let pos = Vec2::new(x as f32 * 40f32 + 20f32, y as f32 * 40f32 + 20f32);
+                    let rot = (x * 18 + y) as f32 * 0.37f32;
+                    let (hull, corners) = map.tank_hull(pos, rot);
+                    let outside = corners.iter().any(|c| c.x() < 0f32 || c.y() < 0f32 || c.x() > 1280f32 || c.y() > 720f32);
+                    let linear = outside || map.shapes.iter().any(|shape| Map::touches_hull(shape, pos, &hull, &corners));
+                    assert_eq!(map.blocks_tank(pos, rot), linear, "{} {:?} {}", path, pos, rot);
+                }
+            }
+        }
+    }
+}
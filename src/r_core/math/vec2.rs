@@ -92,7 +92,7 @@ impl Vec2 {
         *self /= sum
     }
 
-    pub fn dot(&mut self, other: Vec2) -> f32 {
+    pub fn dot(&self, other: Vec2) -> f32 {
         (*self * other).sum()
     }
 
@@ -106,6 +106,12 @@ impl Vec2 {
     }
 }
 
+impl PartialEq for Vec2 {
+    fn eq(&self, other: &Vec2) -> bool {
+        self.x() == other.x() && self.y() == other.y()
+    }
+}
+
 impl Add<Vec2> for Vec2 {
     type Output = Vec2;
 
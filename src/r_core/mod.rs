@@ -1,6 +1,12 @@
 pub mod state;
 pub mod env;
+pub mod error;
+pub mod kinematics;
+pub mod protocol;
 pub mod predictor;
 pub mod controller;
 pub mod map;
-pub mod math;
\ No newline at end of file
+pub mod math;
+pub mod replay;
+pub mod rules;
+pub mod sim;
\ No newline at end of file
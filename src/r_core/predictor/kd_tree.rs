@@ -0,0 +1,429 @@
+use crate::r_core::predictor::knn::{Searcher, DimensionError, check_dims};
+
+//Entries per leaf before it is split
+const BUCKET: usize = 16;
+
+#[derive(Debug, Clone)]
+enum Node {
+    Leaf(Vec<usize>),
+    Split {
+        dim: usize,
+        value: f32,
+        left: usize,
+        right: usize,
+    },
+}
+
+//Bounded kd-tree, once full every add evicts the oldest entry. Entries live in slots that are
+//reused in insertion order, the tree only holds slot indices in bucketed leaves.
+#[derive(Debug, Clone)]
+pub struct KdTree<T: Copy> {
+    nodes: Vec<Node>,
+    coords: Vec<f32>,
+    values: Vec<T>,
+    weights: Option<Vec<f32>>,
+    dims: usize,
+    cap: usize,
+    next: usize,
+    added: usize,
+}
+
+impl<T: Copy> KdTree<T> {
+    pub fn new(dims: usize, cap: usize) -> Self {
+        Self {
+            nodes: vec![Node::Leaf(vec![])],
+            coords: vec![],
+            values: vec![],
+            weights: None,
+            dims,
+            cap,
+            next: 0,
+            added: 0,
+        }
+    }
+
+    //Each dimension's difference is multiplied by its weight before squaring. Like in
+    //LinearSearcher the weights only apply to distances, points are stored and split on as
+    //given, so entries come back unweighted and can be added to a tree with other weights.
+    pub fn with_weights(dims: usize, cap: usize, weights: &[f32]) -> Result<Self, DimensionError> {
+        check_dims(dims, weights)?;
+        let mut tree = Self::new(dims, cap);
+        tree.weights = Some(weights.to_vec());
+        Ok(tree)
+    }
+
+    pub fn dims(&self) -> usize {
+        self.dims
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    fn point(&self, slot: usize) -> &[f32] {
+        &self.coords[slot * self.dims..(slot + 1) * self.dims]
+    }
+
+    fn weight(&self, dim: usize) -> f32 {
+        self.weights.as_ref().map_or(1f32, |weights| weights[dim])
+    }
+
+    //Entries from oldest to newest
+    pub fn entries(&self) -> Vec<(Vec<f32>, T)> {
+        let start = if self.values.len() == self.cap { self.next } else { 0 };
+        (0..self.values.len())
+            .map(|offset| (start + offset) % self.values.len())
+            .map(|slot| (self.point(slot).to_vec(), self.values[slot]))
+            .collect()
+    }
+
+    pub fn add(&mut self, data_point: &[f32], value: T) -> Result<(), DimensionError> {
+        check_dims(self.dims, data_point)?;
+        //Like LinearSearcher, a tree without room keeps nothing
+        if self.cap == 0 {
+            return Ok(());
+        }
+        let point = data_point;
+
+        let slot = self.next;
+        if slot < self.values.len() {
+            self.remove(slot);
+            self.coords[slot * self.dims..(slot + 1) * self.dims].copy_from_slice(point);
+            self.values[slot] = value;
+        } else {
+            self.coords.extend_from_slice(point);
+            self.values.push(value);
+        }
+        self.next = (self.next + 1) % self.cap;
+
+        //Evictions and skewed inserts unbalance the tree, rebuilding every cap adds keeps it cheap
+        self.added += 1;
+        if self.added >= self.cap.max(BUCKET * 4) {
+            self.rebuild();
+        } else {
+            self.insert(slot);
+        }
+        Ok(())
+    }
+
+    fn leaf_of(&self, point: &[f32]) -> usize {
+        let mut node = 0;
+        loop {
+            match &self.nodes[node] {
+                Node::Leaf(_) => return node,
+                Node::Split { dim, value, left, right } => {
+                    node = if point[*dim] < *value { *left } else { *right };
+                }
+            }
+        }
+    }
+
+    fn insert(&mut self, slot: usize) {
+        let leaf = self.leaf_of(self.point(slot));
+        let full = match &mut self.nodes[leaf] {
+            Node::Leaf(slots) => {
+                slots.push(slot);
+                slots.len() > BUCKET
+            }
+            Node::Split { .. } => unreachable!()
+        };
+        if full {
+            let slots = match std::mem::replace(&mut self.nodes[leaf], Node::Leaf(vec![])) {
+                Node::Leaf(slots) => slots,
+                Node::Split { .. } => unreachable!()
+            };
+            self.nodes[leaf] = self.build(slots);
+        }
+    }
+
+    fn remove(&mut self, slot: usize) {
+        let leaf = self.leaf_of(self.point(slot));
+        if let Node::Leaf(slots) = &mut self.nodes[leaf] {
+            if let Some(at) = slots.iter().position(|&other| other == slot) {
+                slots.swap_remove(at);
+            }
+        }
+    }
+
+    fn rebuild(&mut self) {
+        self.added = 0;
+        self.nodes.clear();
+        self.nodes.push(Node::Leaf(vec![]));
+        let root = self.build((0..self.values.len()).collect());
+        self.nodes[0] = root;
+    }
+
+    //Splits at the middle of the widest dimension until every leaf fits a bucket
+    fn build(&mut self, slots: Vec<usize>) -> Node {
+        if slots.len() <= BUCKET {
+            return Node::Leaf(slots);
+        }
+        let mut widest = (0, 0f32, 0f32);
+        for dim in 0..self.dims {
+            let mut min = f32::INFINITY;
+            let mut max = f32::NEG_INFINITY;
+            for &slot in &slots {
+                let x = self.coords[slot * self.dims + dim];
+                min = min.min(x);
+                max = max.max(x);
+            }
+            if max - min > widest.2 - widest.1 {
+                widest = (dim, min, max);
+            }
+        }
+        let (dim, min, max) = widest;
+        let value = min + (max - min) * 0.5f32;
+        if !(max > min) || !(value > min) {
+            return Node::Leaf(slots);
+        }
+        let (left, right): (Vec<usize>, Vec<usize>) = slots
+            .into_iter()
+            .partition(|&slot| self.coords[slot * self.dims + dim] < value);
+        let left = self.build(left);
+        let right = self.build(right);
+        self.nodes.push(left);
+        self.nodes.push(right);
+        Node::Split {
+            dim,
+            value,
+            left: self.nodes.len() - 2,
+            right: self.nodes.len() - 1,
+        }
+    }
+
+    pub fn search(&mut self, search_point: &[f32]) -> Result<Option<T>, DimensionError> {
+        Ok(self.search_k(search_point, 1)?.first().map(|&(_, value)| value))
+    }
+
+    //The k closest entries with their distances, closest first
+    pub fn search_k(&self, search_point: &[f32], k: usize) -> Result<Vec<(f32, T)>, DimensionError> {
+        check_dims(self.dims, search_point)?;
+        if self.values.is_empty() || k == 0 {
+            return Ok(vec![]);
+        }
+        let mut closest = Vec::with_capacity(k + 1);
+        self.search_node(0, search_point, k, &mut closest);
+        Ok(closest
+            .into_iter()
+            .map(|(dist, slot): (f32, usize)| (dist.sqrt(), self.values[slot]))
+            .collect())
+    }
+
+    fn search_node(&self, node: usize, query: &[f32], k: usize, closest: &mut Vec<(f32, usize)>) {
+        match &self.nodes[node] {
+            Node::Leaf(slots) => {
+                for &slot in slots {
+                    let dist = self.point(slot)
+                        .iter()
+                        .zip(query.iter())
+                        .enumerate()
+                        .map(|(dim, (a, b))| ((a - b) * self.weight(dim)).powi(2))
+                        .sum::<f32>();
+                    if closest.len() == k && closest.last().map_or(true, |&(worst, _)| dist >= worst) {
+                        continue;
+                    }
+                    let at = closest.iter().position(|&(other, _)| dist < other).unwrap_or(closest.len());
+                    closest.insert(at, (dist, slot));
+                    closest.truncate(k);
+                }
+            }
+            Node::Split { dim, value, left, right } => {
+                let diff = query[*dim] - *value;
+                let (near, far) = if diff < 0f32 { (*left, *right) } else { (*right, *left) };
+                self.search_node(near, query, k, closest);
+                let bound = (diff * self.weight(*dim)).powi(2);
+                if closest.len() < k || bound < closest[closest.len() - 1].0 {
+                    self.search_node(far, query, k, closest);
+                }
+            }
+        }
+    }
+}
+
+impl<T: Copy> Searcher<T> for KdTree<T> {
+    fn dims(&self) -> usize {
+        KdTree::dims(self)
+    }
+
+    fn add(&mut self, data_point: &[f32], value: T) -> Result<(), DimensionError> {
+        KdTree::add(self, data_point, value)
+    }
+
+    fn search(&mut self, search_point: &[f32]) -> Result<Option<T>, DimensionError> {
+        KdTree::search(self, search_point)
+    }
+
+    fn search_k(&self, search_point: &[f32], k: usize) -> Result<Vec<(f32, T)>, DimensionError> {
+        KdTree::search_k(self, search_point, k)
+    }
+
+    fn len(&self) -> usize {
+        KdTree::len(self)
+    }
+
+    fn entries(&self) -> Vec<(Vec<f32>, T)> {
+        KdTree::entries(self)
+    }
+
+    fn clear(&mut self) {
+        self.nodes = vec![Node::Leaf(vec![])];
+        self.coords.clear();
+        self.values.clear();
+        self.next = 0;
+        self.added = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate test;
+
+    use super::*;
+    use crate::r_core::predictor::knn::LinearSearcher;
+    use test::Bencher;
+
+    fn points(count: usize, dims: usize) -> Vec<Vec<f32>> {
+        let mut seed = 0x1234_5679u32;
+        let mut next = move || {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            seed as f32 / u32::MAX as f32
+        };
+        (0..count).map(|_| (0..dims).map(|_| next()).collect()).collect()
+    }
+
+    fn brute_force(data: &[(Vec<f32>, usize)], weights: &[f32], point: &[f32], k: usize) -> Vec<usize> {
+        let mut all = data
+            .iter()
+            .map(|(other, index)| {
+                let dist = other.iter().zip(point).zip(weights)
+                    .map(|((a, b), w)| ((a - b) * w).powi(2))
+                    .sum::<f32>();
+                (dist, *index)
+            })
+            .collect::<Vec<_>>();
+        all.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+        all.into_iter().take(k).map(|(_, index)| index).collect()
+    }
+
+    #[test]
+    fn matches_brute_force() {
+        let data = points(3000, 4).into_iter().enumerate().map(|(index, point)| (point, index)).collect::<Vec<_>>();
+        let mut tree = KdTree::new(4, 10000);
+        for (point, index) in &data {
+            tree.add(point, *index).unwrap();
+        }
+        assert_eq!(tree.len(), data.len());
+        for query in points(200, 4) {
+            for &k in &[1, 4, 30] {
+                let found = tree.search_k(&query, k).unwrap().into_iter().map(|(_, index)| index).collect::<Vec<_>>();
+                assert_eq!(found, brute_force(&data, &[1f32; 4], &query, k));
+            }
+        }
+    }
+
+    #[test]
+    fn weights_scale_dimensions() {
+        let weights = [1f32, 0f32, 4f32];
+        let data = points(1000, 3).into_iter().enumerate().map(|(index, point)| (point, index)).collect::<Vec<_>>();
+        let mut tree = KdTree::with_weights(3, 1000, &weights).unwrap();
+        for (point, index) in &data {
+            tree.add(point, *index).unwrap();
+        }
+        for query in points(100, 3) {
+            let found = tree.search_k(&query, 5).unwrap().into_iter().map(|(_, index)| index).collect::<Vec<_>>();
+            assert_eq!(found, brute_force(&data, &weights, &query, 5));
+        }
+    }
+
+    #[test]
+    fn weights_only_apply_to_distances() {
+        let weights = [2f32, 0.5f32, 3f32, 1f32];
+        let data = points(500, 4).into_iter().enumerate().map(|(index, point)| (point, index)).collect::<Vec<_>>();
+        let mut tree = KdTree::with_weights(4, 1000, &weights).unwrap();
+        let mut linear = LinearSearcher::with_weights(4, 1000, &weights).unwrap();
+        for (point, index) in &data {
+            tree.add(point, *index).unwrap();
+            linear.add(point, *index).unwrap();
+        }
+        assert_eq!(tree.entries(), data);
+        //Restoring the entries does not weight them twice
+        let mut restored = KdTree::with_weights(4, 1000, &weights).unwrap();
+        for (point, index) in tree.entries() {
+            restored.add(&point, index).unwrap();
+        }
+        for query in points(50, 4) {
+            let found = tree.search_k(&query, 5).unwrap();
+            let expected = linear.search_k(&query, 5).unwrap();
+            assert_eq!(found.iter().map(|&(_, index)| index).collect::<Vec<_>>(),
+                       expected.iter().map(|&(_, index)| index).collect::<Vec<_>>());
+            for ((a, _), (b, _)) in found.iter().zip(expected.iter()) {
+                assert!((a - b).abs() < 1e-5f32);
+            }
+            assert_eq!(restored.search_k(&query, 5).unwrap(), found);
+        }
+    }
+
+    #[test]
+    fn evicts_oldest() {
+        let cap = 500;
+        let data = points(2345, 2).into_iter().enumerate().map(|(index, point)| (point, index)).collect::<Vec<_>>();
+        let mut tree = KdTree::new(2, cap);
+        for (point, index) in &data {
+            tree.add(point, *index).unwrap();
+            assert_eq!(tree.len(), (index + 1).min(cap));
+        }
+        let live = &data[data.len() - cap..];
+        for query in points(100, 2) {
+            let found = tree.search_k(&query, 10).unwrap().into_iter().map(|(_, index)| index).collect::<Vec<_>>();
+            assert_eq!(found, brute_force(live, &[1f32; 2], &query, 10));
+        }
+        let all = tree.search_k(&[0.5f32, 0.5f32], cap * 2).unwrap();
+        assert_eq!(all.len(), cap);
+        assert!(all.iter().all(|&(_, index)| index >= data.len() - cap));
+
+        let mut empty = KdTree::new(2, 0);
+        empty.add(&data[0].0, 0).unwrap();
+        assert_eq!(empty.len(), 0);
+        assert!(empty.search_k(&data[0].0, 1).unwrap().is_empty());
+        assert!(empty.add(&[0f32; 3], 0).is_err());
+    }
+
+    #[test]
+    fn switches_behind_the_trait() {
+        let mut searchers: Vec<Box<dyn Searcher<usize>>> = vec![
+            Box::new(LinearSearcher::new(4, 100)),
+            Box::new(KdTree::new(4, 100)),
+        ];
+        for (index, point) in points(100, 4).iter().enumerate() {
+            for searcher in searchers.iter_mut() {
+                searcher.add(point, index).unwrap();
+            }
+        }
+        for query in points(20, 4) {
+            let linear = searchers[0].search(&query).unwrap();
+            assert_eq!(linear, searchers[1].search(&query).unwrap());
+        }
+    }
+
+    #[bench]
+    fn bench_search_linear(b: &mut Bencher) {
+        let mut searcher = LinearSearcher::new(4, 50000);
+        for (index, point) in points(50000, 4).iter().enumerate() {
+            searcher.add(point, index).unwrap();
+        }
+        let queries = points(100, 4);
+        b.iter(|| queries.iter().filter_map(|query| searcher.search_k(query, 8).unwrap().first().copied()).count());
+    }
+
+    #[bench]
+    fn bench_search_kd_tree(b: &mut Bencher) {
+        let mut tree = KdTree::new(4, 50000);
+        for (index, point) in points(50000, 4).iter().enumerate() {
+            tree.add(point, index).unwrap();
+        }
+        let queries = points(100, 4);
+        b.iter(|| queries.iter().filter_map(|query| tree.search_k(query, 8).unwrap().first().copied()).count());
+    }
+}
@@ -1,40 +1,159 @@
+use std::fmt;
 
-//This can later be made into a Kd-tree
+//Nearest neighbour storage the predictor can run on. Every point, stored or searched for,
+//must have the dimension the searcher was created with.
+pub trait Searcher<T: Copy> {
+    fn dims(&self) -> usize;
+    fn add(&mut self, data_point: &[f32], value: T) -> Result<(), DimensionError>;
+    fn search(&mut self, search_point: &[f32]) -> Result<Option<T>, DimensionError>;
+    fn search_k(&self, search_point: &[f32], k: usize) -> Result<Vec<(f32, T)>, DimensionError>;
+    fn len(&self) -> usize;
+    //Every stored point with its value, oldest first, so adding them back in order restores the searcher
+    fn entries(&self) -> Vec<(Vec<f32>, T)>;
+    //Forgets every point, the dimensions, capacity and weights stay
+    fn clear(&mut self);
+}
+
+//A point with another number of dimensions than the searcher was created with
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct DimensionError {
+    pub expected: usize,
+    pub found: usize,
+}
+
+impl fmt::Display for DimensionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "expected a point with {} dimensions, got {}", self.expected, self.found)
+    }
+}
+
+impl std::error::Error for DimensionError {}
+
+pub fn check_dims(expected: usize, point: &[f32]) -> Result<(), DimensionError> {
+    if point.len() == expected {
+        Ok(())
+    } else {
+        Err(DimensionError {
+            expected,
+            found: point.len(),
+        })
+    }
+}
+
+//Scans every entry, see KdTree for large logs. Entries live in a ring buffer of fixed slots,
+//each slot a run of f32x4 chunks in one flat vector, so a full searcher overwrites its oldest entry.
 #[derive(Debug, Clone)]
 pub struct LinearSearcher<T: Copy> {
-    data: Vec<(Vec<packed_simd::f32x4>, T)>,
+    points: Vec<packed_simd::f32x4>,
+    values: Vec<T>,
+    weights: Option<Vec<packed_simd::f32x4>>,
+    dims: usize,
     cap: usize,
+    next: usize,
+}
+
+//The last chunk is padded with zeros, which add nothing to the distance
+fn pack(point: &[f32]) -> Vec<packed_simd::f32x4> {
+    point
+        .chunks(4)
+        .map(|chunk| {
+            let mut padded = [0f32; 4];
+            padded[..chunk.len()].copy_from_slice(chunk);
+            packed_simd::f32x4::from_slice_unaligned(&padded)
+        })
+        .collect::<Vec<packed_simd::f32x4>>()
 }
 
 impl<T: Copy> LinearSearcher<T> {
-    pub fn new(cap: usize) -> Self {
+    pub fn new(dims: usize, cap: usize) -> Self {
         Self {
-            data: vec![],
+            points: vec![],
+            values: vec![],
+            weights: None,
+            dims,
             cap,
+            next: 0,
         }
     }
 
-    pub fn add(&mut self, data_point: &[f32], value: T) {
-        let search_pack = data_point
-            .chunks(4)
-            .map(|chunk| packed_simd::f32x4::from_slice_unaligned(chunk))
-            .collect::<Vec<packed_simd::f32x4>>();
-        if self.data.len() > self.cap {
-            self.data.remove(0);
-        }
-        self.data.push((search_pack, value));
+    //Each dimension's difference is multiplied by its weight before squaring
+    pub fn with_weights(dims: usize, cap: usize, weights: &[f32]) -> Result<Self, DimensionError> {
+        let mut searcher = Self::new(dims, cap);
+        searcher.set_weights(weights)?;
+        Ok(searcher)
     }
 
-    pub fn search(&mut self, search_point: &[f32]) -> Option<T> {
-        let search_pack = search_point
-            .chunks(4)
-            .map(|chunk| packed_simd::f32x4::from_slice_unaligned(chunk))
-            .collect::<Vec<packed_simd::f32x4>>();
+    pub fn set_weights(&mut self, weights: &[f32]) -> Result<(), DimensionError> {
+        check_dims(self.dims, weights)?;
+        self.weights = Some(pack(weights));
+        Ok(())
+    }
+
+    pub fn dims(&self) -> usize {
+        self.dims
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    //Entries from oldest to newest
+    pub fn entries(&self) -> Vec<(Vec<f32>, T)> {
+        let chunks = self.chunks();
+        let start = if self.values.len() == self.cap { self.next } else { 0 };
+        (0..self.values.len())
+            .map(|offset| (start + offset) % self.values.len())
+            .map(|slot| {
+                let mut point = vec![0f32; chunks * 4];
+                for (index, chunk) in self.points[slot * chunks..(slot + 1) * chunks].iter().enumerate() {
+                    chunk.write_to_slice_unaligned(&mut point[index * 4..(index + 1) * 4]);
+                }
+                point.truncate(self.dims);
+                (point, self.values[slot])
+            })
+            .collect()
+    }
 
-        let mut min_distance = f32::INFINITY;
-        let mut closest_index = None;
-        for (index, (data_point, _)) in self.data.iter().enumerate() {
-            let dist = data_point
+    fn chunks(&self) -> usize {
+        (self.dims + 3) / 4
+    }
+
+    pub fn add(&mut self, data_point: &[f32], value: T) -> Result<(), DimensionError> {
+        check_dims(self.dims, data_point)?;
+        if self.cap == 0 {
+            return Ok(());
+        }
+        let search_pack = pack(data_point);
+        let chunks = self.chunks();
+        if self.values.is_empty() {
+            self.points.reserve(self.cap * chunks);
+            self.values.reserve(self.cap);
+        }
+        let slot = self.next;
+        if slot < self.values.len() {
+            self.points[slot * chunks..(slot + 1) * chunks].copy_from_slice(&search_pack);
+            self.values[slot] = value;
+        } else {
+            self.points.extend_from_slice(&search_pack);
+            self.values.push(value);
+        }
+        self.next = (slot + 1) % self.cap;
+        Ok(())
+    }
+
+    fn distance(&self, data_point: &[packed_simd::f32x4], search_pack: &[packed_simd::f32x4]) -> f32 {
+        match &self.weights {
+            Some(weights) => data_point
+                .iter()
+                .zip(search_pack.iter())
+                .zip(weights.iter())
+                .map(|((d, s), w)| {
+                    let diff = (*d - *s) * *w;
+                    diff * diff
+                })
+                .sum::<packed_simd::f32x4>()
+                .sum(),
+            None => data_point
                 .iter()
                 .zip(search_pack.iter())
                 .map(|(d, s)| {
@@ -42,16 +161,278 @@ impl<T: Copy> LinearSearcher<T> {
                     diff * diff
                 })
                 .sum::<packed_simd::f32x4>()
-                .sum();
-            if dist < min_distance {
-                min_distance = dist;
-                closest_index = Some(index);
+                .sum()
+        }
+    }
+
+    pub fn search(&mut self, search_point: &[f32]) -> Result<Option<T>, DimensionError> {
+        Ok(self.search_k(search_point, 1)?.first().map(|&(_, value)| value))
+    }
+
+    //The k closest entries with their distances, closest first
+    pub fn search_k(&self, search_point: &[f32], k: usize) -> Result<Vec<(f32, T)>, DimensionError> {
+        check_dims(self.dims, search_point)?;
+        if self.values.is_empty() || k == 0 {
+            return Ok(vec![]);
+        }
+        let search_pack = pack(search_point);
+
+        let chunks = self.chunks();
+        let mut closest: Vec<(f32, usize)> = Vec::with_capacity(k + 1);
+        for index in 0..self.values.len() {
+            let dist = self.distance(&self.points[index * chunks..(index + 1) * chunks], &search_pack);
+            if closest.len() == k && closest.last().map_or(true, |&(worst, _)| dist >= worst) {
+                continue;
             }
+            let at = closest.iter().position(|&(other, _)| dist < other).unwrap_or(closest.len());
+            closest.insert(at, (dist, index));
+            closest.truncate(k);
         }
-        if let Some(index) = closest_index {
-            Some(self.data[index].1)
-        } else {
-            None
+        Ok(closest
+            .into_iter()
+            .map(|(dist, index)| (dist.sqrt(), self.values[index]))
+            .collect())
+    }
+}
+
+impl<T: Copy> Searcher<T> for LinearSearcher<T> {
+    fn dims(&self) -> usize {
+        LinearSearcher::dims(self)
+    }
+
+    fn add(&mut self, data_point: &[f32], value: T) -> Result<(), DimensionError> {
+        LinearSearcher::add(self, data_point, value)
+    }
+
+    fn search(&mut self, search_point: &[f32]) -> Result<Option<T>, DimensionError> {
+        LinearSearcher::search(self, search_point)
+    }
+
+    fn search_k(&self, search_point: &[f32], k: usize) -> Result<Vec<(f32, T)>, DimensionError> {
+        LinearSearcher::search_k(self, search_point, k)
+    }
+
+    fn len(&self) -> usize {
+        LinearSearcher::len(self)
+    }
+
+    fn entries(&self) -> Vec<(Vec<f32>, T)> {
+        LinearSearcher::entries(self)
+    }
+
+    fn clear(&mut self) {
+        self.points.clear();
+        self.values.clear();
+        self.next = 0;
+    }
+}
+
+//Spreads neighbours' values in -1..1 (e.g. guess factors) over bins with a gaussian kernel,
+//closer neighbours counting more. Bin i stands for -1 + 2i / (bins - 1).
+pub fn kernel_density(neighbours: &[(f32, f32)], bins: usize, bandwidth: f32) -> Vec<f32> {
+    let mut density = vec![0f32; bins];
+    let step = if bins > 1 { 2f32 / (bins - 1) as f32 } else { 0f32 };
+    for &(dist, value) in neighbours {
+        let weight = 1f32 / (1f32 + dist);
+        for (index, bin) in density.iter_mut().enumerate() {
+            let offset = (-1f32 + index as f32 * step - value) / bandwidth;
+            *bin += weight * (-0.5f32 * offset * offset).exp();
+        }
+    }
+    density
+}
+
+//Value of the densest bin
+pub fn density_peak(density: &[f32]) -> Option<f32> {
+    let mut best = None;
+    for (index, &bin) in density.iter().enumerate() {
+        if best.map_or(true, |(_, peak)| bin > peak) {
+            best = Some((index, bin));
+        }
+    }
+    best.map(|(index, _)| if density.len() > 1 {
+        -1f32 + index as f32 * 2f32 / (density.len() - 1) as f32
+    } else {
+        0f32
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate test;
+
+    use super::*;
+    use test::Bencher;
+
+    fn points(count: usize, dims: usize) -> Vec<Vec<f32>> {
+        let mut seed = 0x9e37_79b9u32;
+        let mut next = move || {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            seed as f32 / u32::MAX as f32
+        };
+        (0..count).map(|_| (0..dims).map(|_| next()).collect()).collect()
+    }
+
+    fn brute_force(data: &[Vec<f32>], weights: &[f32], point: &[f32], k: usize) -> Vec<(f32, usize)> {
+        let mut all = data
+            .iter()
+            .enumerate()
+            .map(|(index, other)| {
+                let dist = other.iter().zip(point).zip(weights)
+                    .map(|((a, b), w)| ((a - b) * w).powi(2))
+                    .sum::<f32>();
+                (dist.sqrt(), index)
+            })
+            .collect::<Vec<_>>();
+        all.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+        all.truncate(k);
+        all
+    }
+
+    #[test]
+    fn search_k_matches_brute_force() {
+        let data = points(500, 8);
+        let weights = [1f32, 2f32, 0.5f32, 1f32, 0f32, 3f32, 1f32, 1f32];
+        let mut plain = LinearSearcher::new(8, 1000);
+        let mut weighted = LinearSearcher::with_weights(8, 1000, &weights).unwrap();
+        for (index, point) in data.iter().enumerate() {
+            plain.add(point, index).unwrap();
+            weighted.add(point, index).unwrap();
+        }
+        for query in points(50, 8).iter().map(|point| point.iter().map(|x| x * 1.2f32).collect::<Vec<_>>()) {
+            for &k in &[1, 5, 20] {
+                let expected = brute_force(&data, &[1f32; 8], &query, k);
+                let found = plain.search_k(&query, k).unwrap();
+                assert_eq!(found.iter().map(|&(_, index)| index).collect::<Vec<_>>(),
+                           expected.iter().map(|&(_, index)| index).collect::<Vec<_>>());
+                for (&(a, _), &(b, _)) in found.iter().zip(expected.iter()) {
+                    assert!((a - b).abs() < 1e-4);
+                }
+
+                let expected = brute_force(&data, &weights, &query, k);
+                let found = weighted.search_k(&query, k).unwrap();
+                assert_eq!(found.iter().map(|&(_, index)| index).collect::<Vec<_>>(),
+                           expected.iter().map(|&(_, index)| index).collect::<Vec<_>>());
+            }
+            assert_eq!(plain.search(&query).unwrap(), Some(brute_force(&data, &[1f32; 8], &query, 1)[0].1));
+        }
+    }
+
+    #[test]
+    fn search_k_returns_everything_when_short() {
+        let mut searcher = LinearSearcher::new(4, 10);
+        assert!(searcher.search_k(&[0f32; 4], 3).unwrap().is_empty());
+        assert_eq!(searcher.search(&[0f32; 4]).unwrap(), None);
+        searcher.add(&[1f32, 0f32, 0f32, 0f32], 'a').unwrap();
+        searcher.add(&[3f32, 0f32, 0f32, 0f32], 'b').unwrap();
+        assert_eq!(searcher.search_k(&[0f32; 4], 3).unwrap(), vec![(1f32, 'a'), (3f32, 'b')]);
+    }
+
+    #[test]
+    fn density_peaks_at_the_cluster() {
+        let neighbours = [(0.1f32, 0.5f32), (0.2f32, 0.45f32), (0.1f32, 0.55f32), (0.5f32, -0.8f32)];
+        let density = kernel_density(&neighbours, 41, 0.1f32);
+        let peak = density_peak(&density).unwrap();
+        assert!((peak - 0.5f32).abs() < 0.06f32, "{}", peak);
+        assert_eq!(density_peak(&[]), None);
+    }
+
+    #[test]
+    fn holds_exactly_cap_entries() {
+        let cap = 300;
+        let data = points(1000, 4);
+        let mut searcher = LinearSearcher::new(4, cap);
+        for (index, point) in data.iter().enumerate() {
+            searcher.add(point, index).unwrap();
+            assert_eq!(searcher.len(), (index + 1).min(cap));
+        }
+        let all = searcher.search_k(&[0.5f32; 4], cap * 2).unwrap();
+        assert_eq!(all.len(), cap);
+        assert!(all.iter().all(|&(_, index)| index >= data.len() - cap));
+        for query in points(20, 4) {
+            let expected = brute_force(&data[data.len() - cap..], &[1f32; 4], &query, 1)[0].1;
+            assert_eq!(searcher.search(&query).unwrap(), Some(expected + data.len() - cap));
+        }
+
+        let mut empty = LinearSearcher::new(4, 0);
+        empty.add(&data[0], 0).unwrap();
+        assert_eq!(empty.len(), 0);
+    }
+
+    #[test]
+    fn any_dimension_matches_brute_force() {
+        for dims in 1..=13 {
+            let data = points(300, dims);
+            let weights = (0..dims).map(|dim| 0.5f32 + dim as f32 * 0.25f32).collect::<Vec<_>>();
+            let mut plain = LinearSearcher::new(dims, 300);
+            let mut weighted = LinearSearcher::with_weights(dims, 300, &weights).unwrap();
+            for (index, point) in data.iter().enumerate() {
+                plain.add(point, index).unwrap();
+                weighted.add(point, index).unwrap();
+            }
+            for query in points(30, dims) {
+                let expected = brute_force(&data, &vec![1f32; dims], &query, 3);
+                let found = plain.search_k(&query, 3).unwrap();
+                assert_eq!(found.iter().map(|&(_, index)| index).collect::<Vec<_>>(),
+                           expected.iter().map(|&(_, index)| index).collect::<Vec<_>>(), "{} dimensions", dims);
+                for (&(a, _), &(b, _)) in found.iter().zip(expected.iter()) {
+                    assert!((a - b).abs() < 1e-4, "{} dimensions", dims);
+                }
+
+                let expected = brute_force(&data, &weights, &query, 3);
+                let found = weighted.search_k(&query, 3).unwrap();
+                assert_eq!(found.iter().map(|&(_, index)| index).collect::<Vec<_>>(),
+                           expected.iter().map(|&(_, index)| index).collect::<Vec<_>>(), "{} dimensions", dims);
+            }
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn rejects_wrong_dimensions() {
+        let mut searcher = LinearSearcher::new(5, 10);
+        searcher.add(&[0f32; 5], 0).unwrap();
+        let wrong = |result: Result<(), DimensionError>, found| match result {
+            Err(DimensionError { expected: 5, found: f }) => assert_eq!(f, found),
+            other => panic!("expected a dimension error, got {:?}", other),
+        };
+        wrong(searcher.add(&[0f32; 4], 1), 4);
+        wrong(searcher.add(&[0f32; 8], 1), 8);
+        wrong(searcher.search(&[0f32; 6]).map(|_| ()), 6);
+        wrong(searcher.search_k(&[0f32; 3], 2).map(|_| ()), 3);
+        wrong(searcher.set_weights(&[1f32; 4]), 4);
+        assert!(LinearSearcher::<usize>::with_weights(5, 10, &[1f32; 2]).is_err());
+        assert_eq!(searcher.len(), 1);
+    }
+
+    //Time per insert should not depend on the cap once the searcher is full
+    fn bench_add(b: &mut Bencher, cap: usize) {
+        let data = points(1000, 8);
+        let mut searcher = LinearSearcher::new(8, cap);
+        for index in 0..cap {
+            searcher.add(&data[index % data.len()], index).unwrap();
+        }
+        b.iter(|| {
+            for (index, point) in data.iter().enumerate() {
+                searcher.add(point, index).unwrap();
+            }
+            searcher.len()
+        });
+    }
+
+    #[bench]
+    fn bench_add_full_10k(b: &mut Bencher) {
+        bench_add(b, 10000);
+    }
+
+    #[bench]
+    fn bench_add_full_50k(b: &mut Bencher) {
+        bench_add(b, 50000);
+    }
+
+    #[bench]
+    fn bench_add_full_100k(b: &mut Bencher) {
+        bench_add(b, 100000);
+    }
+}
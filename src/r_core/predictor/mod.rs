@@ -1,2 +1,3 @@
 pub mod knn;
+pub mod kd_tree;
 pub mod predictor;
\ No newline at end of file
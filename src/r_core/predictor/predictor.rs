@@ -0,0 +1,404 @@
+use crate::r_core::predictor::knn::{Searcher, DimensionError, check_dims};
+use crate::r_core::predictor::kd_tree::KdTree;
+use crate::r_core::kinematics::{Kinematics, wrap};
+use crate::r_core::controller::strategy::Action;
+use crate::r_core::state::State;
+use crate::r_core::map::map::Map;
+use crate::r_core::math::vec2::Vec2;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use serde::{Serialize, Deserialize};
+
+const PI: f32 = std::f32::consts::PI;
+
+pub const FEATURES: usize = 4;
+
+//Scales that bring every feature to roughly 0..1
+const DIST_SCALE: f32 = 1000f32;
+const SPEED_SCALE: f32 = 150f32;
+const WALL_SCALE: f32 = 500f32;
+
+const NEIGHBOURS: usize = 8;
+//Step used to extrapolate the opponent before anything has been learned
+const EXTRAPOLATE_STEP: f32 = 0.05f32;
+
+//Bumped whenever a feature changes meaning, saved data of another format is rejected.
+//1 measured the opponent's speed between two frames, 2 takes the smoothed estimate from Env.
+const FORMAT: u32 = 2;
+
+//What gets written to disk between matches
+#[derive(Serialize, Deserialize)]
+struct Saved {
+    //Files from before the format was saved are format 1
+    #[serde(default = "first_format")]
+    format: u32,
+    features: usize,
+    entries: Vec<(Vec<f32>, Vec2)>,
+}
+
+fn first_format() -> u32 {
+    1
+}
+
+//Where the data learned against an opponent on a map is kept, one directory per opponent
+pub fn data_path(dir: &str, opponent: &str, map: &str) -> PathBuf {
+    let map = Path::new(map)
+        .file_stem()
+        .map_or_else(|| map.to_string(), |stem| stem.to_string_lossy().into_owned());
+    let clean = |name: &str| name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+        .collect::<String>();
+    Path::new(dir).join(clean(opponent)).join(format!("{}.json", clean(&map)))
+}
+
+//A situation whose outcome is not known yet, resolved once its bullet would have arrived
+#[derive(Debug, Copy, Clone)]
+struct Pending {
+    features: [f32; FEATURES],
+    time: f32,
+    flight_time: f32,
+    pos: Vec2,
+    bearing: f32,
+}
+
+//Learns where the opponent goes while a bullet is in the air. Displacements are stored
+//relative to the line of sight so they carry over between positions on the map.
+pub struct Predictor {
+    searcher: Box<dyn Searcher<Vec2>>,
+    pending: VecDeque<Pending>,
+    last: Option<State>,
+}
+
+impl Predictor {
+    pub fn new(cap: usize) -> Self {
+        Self {
+            searcher: Box::new(KdTree::new(FEATURES, cap)),
+            pending: VecDeque::new(),
+            last: None,
+        }
+    }
+
+    //The searcher has to take FEATURES dimensions
+    pub fn with_searcher(searcher: Box<dyn Searcher<Vec2>>) -> Result<Self, DimensionError> {
+        if searcher.dims() != FEATURES {
+            return Err(DimensionError {
+                expected: FEATURES,
+                found: searcher.dims(),
+            });
+        }
+        let mut predictor = Self::new(0);
+        predictor.searcher = searcher;
+        Ok(predictor)
+    }
+
+    //Distance, the opponent's heading relative to the line of sight, its speed along
+    //its heading and how much room it has in front of it
+    pub fn features(state: &State, map: &Map) -> [f32; FEATURES] {
+        let heading = -state.opp.r;
+        let relative = wrap(heading - state.abs_bearing);
+
+        let forward = Vec2::from_angle(heading);
+        let speed = state.opp_motion.speed;
+        let dir = if speed < 0f32 { forward * -1f32 } else { forward };
+        let wall = map.cast(state.opp.pos, dir, 0f32, WALL_SCALE)
+            .map_or(WALL_SCALE, |(_, hit)| hit.dist);
+
+        [
+            state.dist / DIST_SCALE,
+            relative / PI,
+            speed / SPEED_SCALE,
+            wall / WALL_SCALE,
+        ]
+    }
+
+    //Call once per frame with the newest state
+    pub fn record(&mut self, state: &State, map: &Map) {
+        if let Some(last) = self.last {
+            if state.time < last.time {
+                self.pending.clear();
+                self.last = None;
+            } else if state.time == last.time {
+                return;
+            }
+        }
+        let features = Self::features(state, map);
+        self.pending.push_back(Pending {
+            features,
+            time: state.time,
+            flight_time: state.dist / map.rules().bullet_speed,
+            pos: state.opp.pos,
+            bearing: state.abs_bearing,
+        });
+        while let Some(pending) = self.pending.front() {
+            if state.time - pending.time < pending.flight_time {
+                break;
+            }
+            let mut displacement = state.opp.pos - pending.pos;
+            displacement.rotate(Vec2::from_angle(-pending.bearing));
+            self.searcher.add(&pending.features, displacement)
+                .expect("searcher dimension is checked on construction");
+            self.pending.pop_front();
+        }
+        self.last = Some(*state);
+    }
+
+    //Where the opponent is expected to be once a bullet fired now reaches it. Until anything
+    //has been learned it is assumed to keep moving the way it has been.
+    pub fn predict(&mut self, state: &State, map: &Map, kinematics: &Kinematics) -> Vec2 {
+        let features = Self::features(state, map);
+        let neighbours = self.searcher.search_k(&features, NEIGHBOURS)
+            .expect("searcher dimension is checked on construction");
+        if neighbours.is_empty() {
+            return Self::extrapolate(state, map, kinematics);
+        }
+        //Closer situations count more
+        let mut displacement = Vec2::new(0f32, 0f32);
+        let mut total = 0f32;
+        for (dist, value) in neighbours {
+            let weight = 1f32 / (1e-3f32 + dist);
+            displacement += value * weight;
+            total += weight;
+        }
+        displacement /= total;
+        displacement.rotate(Vec2::from_angle(state.abs_bearing));
+        state.opp.pos + displacement
+    }
+
+    fn extrapolate(state: &State, map: &Map, kinematics: &Kinematics) -> Vec2 {
+        let motion = state.opp_motion;
+        let action = Action {
+            move_dir: (motion.speed / kinematics.speed()).max(-1f32).min(1f32),
+            turn: (motion.angular_vel / kinematics.turn_rate()).max(-1f32).min(1f32),
+            fire: false,
+        };
+        let (mut pos, mut rot) = (state.opp.pos, state.opp.r);
+        let mut time = 0f32;
+        let flight_time = state.dist / map.rules().bullet_speed;
+        while time < flight_time {
+            let dt = EXTRAPOLATE_STEP.min(flight_time - time);
+            let next = kinematics.predict(pos, rot, action, dt);
+            pos = next.0;
+            rot = next.1;
+            time += dt;
+        }
+        pos
+    }
+
+    //Writes everything learned so far, situations still waiting for their outcome are dropped
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let saved = Saved {
+            format: FORMAT,
+            features: FEATURES,
+            entries: self.searcher.entries(),
+        };
+        let mut out = BufWriter::new(File::create(path)?);
+        serde_json::to_writer(&mut out, &saved)?;
+        out.flush()
+    }
+
+    //Replaces what has been learned with the data saved by an earlier match. A missing file
+    //leaves the predictor as it was, and so does one that cannot be read in full.
+    pub fn load(&mut self, path: &Path) -> io::Result<()> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err)
+        };
+        let saved: Saved = serde_json::from_reader(BufReader::new(file))?;
+        if saved.format != FORMAT {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      format!("saved data is format {}, expected {}", saved.format, FORMAT)));
+        }
+        if saved.features != FEATURES {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      format!("saved data has {} features, expected {}", saved.features, FEATURES)));
+        }
+        for (point, _) in &saved.entries {
+            check_dims(FEATURES, point).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        }
+        self.searcher.clear();
+        for (point, value) in saved.entries {
+            self.searcher.add(&point, value).expect("saved points are checked above");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::r_core::state::{BotData, TankMotion};
+
+    fn state(time: f32) -> State {
+        let bot = BotData {
+            pos: Vec2::new(200f32, 360f32),
+            r: 0f32,
+            can_fire: true,
+        };
+        let opp = BotData {
+            pos: Vec2::new(900f32, 200f32 + (time * 2f32).sin() * 150f32),
+            r: -PI * 0.5f32,
+            can_fire: true,
+        };
+        //Driving back and forth along its heading
+        let speed = (time * 2f32).cos() * 300f32;
+        State {
+            bot,
+            opp,
+            dist: (opp.pos - bot.pos).sq_magnitude().sqrt(),
+            abs_bearing: (opp.pos - bot.pos).angle(),
+            time,
+            bot_motion: TankMotion::still(time),
+            opp_motion: TankMotion {
+                vel: Vec2::new(0f32, speed),
+                speed,
+                ..TankMotion::still(time)
+            },
+        }
+    }
+
+    //The opponent keeps its heading straight down the screen at a constant speed
+    fn driving(time: f32, speed: f32) -> State {
+        let mut state = state(time);
+        state.opp.pos = Vec2::new(900f32, 100f32 + time * speed);
+        state.dist = (state.opp.pos - state.bot.pos).sq_magnitude().sqrt();
+        state.abs_bearing = (state.opp.pos - state.bot.pos).angle();
+        state.opp_motion = TankMotion {
+            vel: Vec2::new(0f32, speed),
+            speed,
+            ..TankMotion::still(time)
+        };
+        state
+    }
+
+    #[test]
+    fn records_once_the_bullet_would_have_arrived() {
+        let map = Map::new(vec![]);
+        let mut predictor = Predictor::new(1000);
+        let first = driving(0f32, 100f32);
+        let flight_time = first.dist / map.rules().bullet_speed;
+        let mut tick = 0;
+        while (tick as f32 / 30f32) < flight_time {
+            predictor.record(&driving(tick as f32 / 30f32, 100f32), &map);
+            //The same frame again changes nothing
+            predictor.record(&driving(tick as f32 / 30f32, 100f32), &map);
+            tick += 1;
+        }
+        assert_eq!(predictor.searcher.len(), 0);
+        let now = driving(tick as f32 / 30f32, 100f32);
+        predictor.record(&now, &map);
+        assert!(predictor.searcher.len() >= 1);
+        //Stored relative to the line of sight at the time
+        let (point, mut displacement) = predictor.searcher.entries()[0].clone();
+        assert_eq!(point, Predictor::features(&first, &map).to_vec());
+        displacement.rotate(Vec2::from_angle(first.abs_bearing));
+        assert!((displacement - (now.opp.pos - first.opp.pos)).sq_magnitude() < 1e-3f32);
+
+        //An earlier time starts over, nothing waiting from before gets resolved
+        predictor.record(&driving(0f32, 100f32), &map);
+        let learned = predictor.searcher.len();
+        predictor.record(&driving(0.5f32, 100f32), &map);
+        assert_eq!(predictor.searcher.len(), learned);
+    }
+
+    #[test]
+    fn predicts_what_it_has_learned() {
+        let map = Map::new(vec![]);
+        let kinematics = Kinematics::new();
+        let mut predictor = Predictor::new(1000);
+        //Nothing learned yet, a still opponent stays and a moving one keeps going
+        let still = driving(1f32, 0f32);
+        assert!((predictor.predict(&still, &map, &kinematics) - still.opp.pos).sq_magnitude() < 1e-6f32);
+        let moving = driving(1f32, 100f32);
+        let ahead = predictor.predict(&moving, &map, &kinematics) - moving.opp.pos;
+        let expected = 100f32 * moving.dist / map.rules().bullet_speed;
+        assert!(ahead.x().abs() < 1e-3f32 && (ahead.y() - expected).abs() < 1f32, "{:?}", ahead);
+
+        //Always backing up at 100px/s, against the way it is facing
+        for tick in 0..300 {
+            predictor.record(&driving(tick as f32 / 30f32, -100f32), &map);
+        }
+        //A situation seen before, its closest neighbour is itself
+        let state = driving(2f32, -100f32);
+        let predicted = predictor.predict(&state, &map, &kinematics) - state.opp.pos;
+        let expected = -100f32 * state.dist / map.rules().bullet_speed;
+        assert!(predicted.x().abs() < 5f32 && (predicted.y() - expected).abs() < 5f32, "{:?}", predicted);
+    }
+
+    #[test]
+    fn saved_data_loads_back() {
+        let map = Map::new(vec![]);
+        let mut predictor = Predictor::new(1000);
+        for tick in 0..600 {
+            predictor.record(&state(tick as f32 / 30f32), &map);
+        }
+        let path = std::env::temp_dir().join(format!("rage_predictor_{}.json", std::process::id()));
+        predictor.save(&path).unwrap();
+
+        let mut loaded = Predictor::new(1000);
+        loaded.load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let expected = predictor.searcher.entries();
+        assert!(!expected.is_empty());
+        assert_eq!(loaded.searcher.entries().len(), expected.len());
+        for ((a, x), (b, y)) in loaded.searcher.entries().iter().zip(expected.iter()) {
+            assert_eq!(a, b);
+            assert!((*x - *y).sq_magnitude() < 1e-6);
+        }
+
+        //Only what was learned matters, a fresh predictor with the same data predicts the same
+        let state = state(30f32);
+        let mut fresh = Predictor::new(1000);
+        fresh.searcher = predictor.searcher;
+        let expected = fresh.predict(&state, &map, &Kinematics::new());
+        assert!((expected - state.opp.pos).sq_magnitude() > 0f32);
+        assert!((loaded.predict(&state, &map, &Kinematics::new()) - expected).sq_magnitude() < 1e-6);
+
+        let mut empty = Predictor::new(1000);
+        empty.load(&path).unwrap();
+        assert_eq!(empty.searcher.len(), 0);
+    }
+
+    #[test]
+    fn other_formats_are_rejected() {
+        let path = std::env::temp_dir().join(format!("rage_predictor_format_{}.json", std::process::id()));
+        //Written before the speed feature was smoothed, same number of features
+        std::fs::write(&path, r#"{"features": 4, "entries": [[[0.5, 0.0, 1.0, 1.0], [10.0, 0.0]]]}"#).unwrap();
+        let mut predictor = Predictor::new(1000);
+        let err = predictor.load(&path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(predictor.searcher.len(), 0);
+
+        std::fs::write(&path, r#"{"format": 2, "features": 4, "entries": [[[0.5, 0.0, 1.0, 1.0], [10.0, 0.0]]]}"#).unwrap();
+        predictor.load(&path).unwrap();
+        assert_eq!(predictor.searcher.len(), 1);
+        //Loading again replaces rather than adds
+        predictor.load(&path).unwrap();
+        assert_eq!(predictor.searcher.len(), 1);
+
+        //A bad entry after good ones loads nothing
+        std::fs::write(&path, r#"{"format": 2, "features": 4, "entries": [
+            [[0.1, 0.0, 1.0, 1.0], [10.0, 0.0]], [[0.2, 0.0, 1.0, 1.0], [10.0, 0.0]], [[0.3, 0.0], [10.0, 0.0]]
+        ]}"#).unwrap();
+        let err = predictor.load(&path).unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(predictor.searcher.entries()[0].0, vec![0.5f32, 0f32, 1f32, 1f32]);
+        assert_eq!(predictor.searcher.len(), 1);
+    }
+
+    #[test]
+    fn data_is_kept_per_opponent_and_map() {
+        assert_eq!(data_path("data", "team 7", "./maps/MapA.json"), Path::new("data").join("team_7").join("MapA.json"));
+        assert_ne!(data_path("data", "a", "./maps/MapA.json"), data_path("data", "a", "./maps/MapB.json"));
+        assert_ne!(data_path("data", "a_b", "c"), data_path("data", "a", "b_c"));
+        //Nothing in a name can leave its directory
+        assert_eq!(data_path("data", "../x", "y"), Path::new("data").join("___x").join("y.json"));
+    }
+}
@@ -0,0 +1,33 @@
+use std::collections::HashMap;
+use serde::{Serialize, Deserialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum DataType {
+    Str(String),
+    Bool(bool),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Projectile {
+    pub x: String,
+    pub y: String,
+    pub vx: i32,
+    pub vy: i32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TankData {
+    pub x: String,
+    pub y: String,
+    pub r: String,
+    pub can_fire: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GameData {
+    #[serde(rename = "tankA")]
+    pub tank_a: TankData,
+    #[serde(rename = "tankB")]
+    pub tank_b: TankData,
+    pub projectiles: HashMap<String, Projectile>,
+}
@@ -0,0 +1,276 @@
+use crate::r_core::protocol::GameData;
+use crate::r_core::controller::controller::{Controller, OutputAction};
+use crate::r_core::controller::strategy;
+use crate::r_core::env::{Env, Team};
+use crate::r_core::map::map::Map;
+use crate::r_core::rules::GameRules;
+use serde::{Serialize, Deserialize};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+//One line of a replay file, times are seconds since the recording started
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Record {
+    Start {
+        team: Team,
+        map: String,
+        //None when the bot was inferring the rules from what it saw
+        #[serde(default)]
+        rules: Option<GameRules>,
+    },
+    Frame {
+        t: f32,
+        data: GameData,
+    },
+    Action {
+        t: f32,
+        action: OutputAction,
+    },
+}
+
+pub struct Recorder {
+    out: BufWriter<File>,
+}
+
+impl Recorder {
+    pub fn create(path: &str, team: Team, map: &str, rules: Option<GameRules>) -> io::Result<Self> {
+        let mut recorder = Self {
+            out: BufWriter::new(File::create(path)?),
+        };
+        recorder.write(&Record::Start {
+            team,
+            map: map.to_string(),
+            rules,
+        })?;
+        Ok(recorder)
+    }
+
+    fn write(&mut self, record: &Record) -> io::Result<()> {
+        serde_json::to_writer(&mut self.out, record)?;
+        self.out.write_all(b"\n")
+    }
+
+    pub fn frame(&mut self, t: f32, data: &GameData) -> io::Result<()> {
+        self.write(&Record::Frame {
+            t,
+            data: data.clone(),
+        })
+    }
+
+    //Flushes so a killed bot still leaves a usable file
+    pub fn action(&mut self, t: f32, action: &OutputAction) -> io::Result<()> {
+        self.write(&Record::Action {
+            t,
+            action: *action,
+        })?;
+        self.out.flush()
+    }
+}
+
+pub fn read(path: &str) -> io::Result<Vec<Record>> {
+    let mut records = vec![];
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        if !line.is_empty() {
+            records.push(serde_json::from_str(&line)?);
+        }
+    }
+    Ok(records)
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct Mismatch {
+    pub frame: usize,
+    pub t: f32,
+    pub recorded: OutputAction,
+    pub replayed: OutputAction,
+}
+
+//The team, map and rules of a recording
+pub fn start(records: &[Record]) -> io::Result<(Team, &str, Option<GameRules>)> {
+    match records.first() {
+        Some(Record::Start { team, map, rules }) => Ok((*team, map, *rules)),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, "replay does not start with a Start record"))
+    }
+}
+
+//Runs a fresh controller with the named strategy over the recorded frames and reports every
+//action that differs from the recorded one. rules replace the recorded ones when given, the
+//controller starts out from the learned data at data like the live bot does. The replayed run
+//is written to recorder when given.
+pub fn replay(records: &[Record],
+              strategy: &str,
+              rules: Option<GameRules>,
+              data: Option<&Path>,
+              mut recorder: Option<&mut Recorder>) -> io::Result<Vec<Mismatch>> {
+    let (team, map, recorded) = start(records)?;
+    let rules = rules.or(recorded);
+    let map = Arc::new(Map::map_from_file(map).with_rules(rules.unwrap_or_default()));
+    let strategy = strategy::by_name(strategy, map)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("unknown strategy {}", strategy)))?;
+    let env = Arc::new(Mutex::new(Env::with_rules(10, rules.unwrap_or_default(), rules.is_none())));
+    let mut controller = Controller::with_strategy(env, strategy, team);
+    if let Some(data) = data {
+        controller.load(data)?;
+    }
+
+    let mut mismatches = vec![];
+    let mut pending = None;
+    let mut frame = 0;
+    for record in records.iter().skip(1) {
+        match record {
+            Record::Start { .. } => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "more than one Start record"));
+            }
+            Record::Frame { t, data } => {
+                if let Some(recorder) = recorder.as_mut() {
+                    recorder.frame(*t, data)?;
+                }
+                pending = match controller.handle(data.clone(), *t) {
+                    Ok(action) => {
+                        if let Some(recorder) = recorder.as_mut() {
+                            recorder.action(*t, &action)?;
+                        }
+                        Some((frame, *t, action))
+                    }
+                    Err(_) => None
+                };
+                frame += 1;
+            }
+            Record::Action { action, .. } => {
+                if let Some((frame, t, replayed)) = pending.take() {
+                    if replayed != *action {
+                        mismatches.push(Mismatch {
+                            frame,
+                            t,
+                            recorded: *action,
+                            replayed,
+                        });
+                    }
+                }
+            }
+        }
+    }
+    Ok(mismatches)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::r_core::sim::sim::Simulator;
+    use crate::r_core::math::vec2::Vec2;
+
+    const MAP: &str = "./maps/MapA.json";
+
+    fn temp_path(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!("rage_replay_{}_{}.jsonl", name, std::process::id()))
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+
+    //Plays a few frames of the simulator against a still opponent and records them, the bot
+    //infers the rules unless given them
+    fn record(path: &str, rules: Option<GameRules>) {
+        let map = Arc::new(Map::map_from_file(MAP).with_rules(rules.unwrap_or_default()));
+        let spawn_a = Simulator::clear_spawn(&map, Vec2::new(200f32, 360f32));
+        let spawn_b = Simulator::clear_spawn(&map, Vec2::new(1080f32, 360f32));
+        let mut sim = Simulator::new(map.clone(), spawn_a, spawn_b);
+        let env = Arc::new(Mutex::new(Env::with_rules(10, rules.unwrap_or_default(), rules.is_none())));
+        let strategy = strategy::by_name("minimum_danger", map).unwrap();
+        let mut controller = Controller::with_strategy(env, strategy, Team::A);
+        let mut recorder = Recorder::create(path, Team::A, MAP, rules).unwrap();
+        for _ in 0..20 {
+            let data = sim.game_data();
+            recorder.frame(sim.time(), &data).unwrap();
+            let action = controller.handle(data, sim.time()).unwrap();
+            recorder.action(sim.time(), &action).unwrap();
+            sim.step(action, OutputAction::new(), 0.05f32);
+        }
+    }
+
+    #[test]
+    fn reads_back_what_was_recorded() {
+        let path = temp_path("round_trip");
+        record(&path, None);
+        let records = read(&path).unwrap();
+        assert_eq!(records.len(), 41);
+        let copy = temp_path("copy");
+        let mut recorder = Recorder::create(&copy, Team::A, MAP, None).unwrap();
+        for record in records.iter().skip(1) {
+            match record {
+                Record::Frame { t, data } => recorder.frame(*t, data).unwrap(),
+                Record::Action { t, action } => recorder.action(*t, action).unwrap(),
+                Record::Start { .. } => panic!("more than one Start record"),
+            }
+        }
+        drop(recorder);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), std::fs::read_to_string(&copy).unwrap());
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&copy).unwrap();
+    }
+
+    #[test]
+    fn replays_its_own_recording() {
+        let path = temp_path("replay");
+        record(&path, None);
+        let mut records = read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(replay(&records, "minimum_danger", None, None, None).unwrap().is_empty());
+
+        let (index, recorded) = records
+            .iter()
+            .enumerate()
+            .filter_map(|(index, record)| match record {
+                Record::Action { action, .. } => Some((index, *action)),
+                _ => None
+            })
+            .nth(10)
+            .unwrap();
+        let tampered = OutputAction {
+            m: -recorded.m - 1,
+            ..recorded
+        };
+        if let Record::Action { action, .. } = &mut records[index] {
+            *action = tampered;
+        }
+        let mismatches = replay(&records, "minimum_danger", None, None, None).unwrap();
+        assert_eq!(mismatches.len(), 1);
+        assert_eq!(mismatches[0].frame, 10);
+        assert_eq!(mismatches[0].recorded, tampered);
+        assert_eq!(mismatches[0].replayed, recorded);
+    }
+
+    #[test]
+    fn replays_under_the_recorded_rules() {
+        let path = temp_path("rules");
+        let rules = GameRules {
+            bullet_speed: 300f32,
+            tank_speed: 100f32,
+            ..GameRules::default()
+        };
+        record(&path, Some(rules));
+        let records = read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(start(&records).unwrap(), (Team::A, MAP, Some(rules)));
+        assert!(replay(&records, "minimum_danger", None, None, None).unwrap().is_empty());
+        assert!(replay(&records, "minimum_danger", Some(rules), None, None).unwrap().is_empty());
+    }
+
+    #[test]
+    fn loads_the_learned_data() {
+        let path = temp_path("data");
+        record(&path, None);
+        let records = read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let data = std::env::temp_dir().join(format!("rage_replay_data_{}.json", std::process::id()));
+        assert!(replay(&records, "minimum_danger", None, Some(&data), None).unwrap().is_empty());
+        std::fs::write(&data, "not saved data").unwrap();
+        let err = replay(&records, "minimum_danger", None, Some(&data), None).unwrap_err();
+        std::fs::remove_file(&data).unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,68 @@
+use crate::r_core::math::vec2::Vec2;
+use serde::{Serialize, Deserialize};
+use std::io;
+
+//Numbers the server plays by. Anything missing from a rules file keeps its default.
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GameRules {
+    pub bullet_speed: f32,
+    //Seconds a bullet flies before it disappears
+    pub bullet_lifetime: f32,
+    pub max_bounces: usize,
+    //Width and height of the tank's hull
+    pub tank_size: Vec2,
+    pub fire_cooldown: f32,
+    //Pixels per second along the heading at full throttle
+    pub tank_speed: f32,
+    //Radians per second
+    pub turn_rate: f32,
+    //Size of the playing field, tanks cannot leave it
+    pub arena: Vec2,
+}
+
+impl Default for GameRules {
+    fn default() -> Self {
+        Self {
+            bullet_speed: 400f32,
+            bullet_lifetime: 6f32,
+            max_bounces: 100,
+            tank_size: Vec2::new(79.6129, 124.67),
+            fire_cooldown: 0.5f32,
+            tank_speed: 150f32,
+            turn_rate: std::f32::consts::PI,
+            arena: Vec2::new(1280f32, 720f32),
+        }
+    }
+}
+
+impl GameRules {
+    pub fn from_file(path: &str) -> io::Result<Self> {
+        let text = std::fs::read_to_string(path)?;
+        serde_json::from_str(&text).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    //Half the hull's size, what a Shape::RotRect for the tank takes
+    pub fn hull_extents(&self) -> Vec2 {
+        self.tank_size * 0.5f32
+    }
+
+    //How far a bullet gets before it disappears
+    pub fn max_dist(&self) -> f32 {
+        self.bullet_speed * self.bullet_lifetime
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_fields_keep_their_defaults() {
+        let rules: GameRules = serde_json::from_str(r#"{"bullet_speed": 500, "max_bounces": 3}"#).unwrap();
+        assert_eq!(rules.bullet_speed, 500f32);
+        assert_eq!(rules.max_bounces, 3);
+        assert_eq!(rules.bullet_lifetime, GameRules::default().bullet_lifetime);
+        assert_eq!(rules.max_dist(), 3000f32);
+    }
+}
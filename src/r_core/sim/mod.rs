@@ -0,0 +1 @@
+pub mod sim;
\ No newline at end of file
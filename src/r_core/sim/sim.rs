@@ -0,0 +1,412 @@
+use crate::r_core::protocol::{GameData, TankData, Projectile};
+use crate::r_core::controller::controller::{Controller, OutputAction};
+use crate::r_core::controller::strategy::Strategy;
+use crate::r_core::env::{Env, Team};
+use crate::r_core::map::map::{Map, Shape, BulletTrajectory};
+use crate::r_core::math::vec2::Vec2;
+use crate::r_core::state::Bullet;
+use crate::r_core::kinematics::wrap;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+const PI: f32 = std::f32::consts::PI;
+
+const HIT_STEP: f32 = 2f32;
+const SPAWN_SEARCH: f32 = 400f32;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Outcome {
+    Running,
+    Won(Team),
+    Draw,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct Tank {
+    pub pos: Vec2,
+    pub rot: f32,
+    reload: f32,
+}
+
+impl Tank {
+    fn new(pos: Vec2, rot: f32) -> Self {
+        Self {
+            pos,
+            rot,
+            reload: 0f32,
+        }
+    }
+
+    //size is the hull's half extents
+    pub fn hull(&self, size: Vec2) -> Shape {
+        Shape::RotRect {
+            pos: self.pos,
+            w_h: size,
+            rot: self.rot,
+        }
+    }
+
+    pub fn forward(&self) -> Vec2 {
+        Vec2::from_angle(-self.rot)
+    }
+
+    //Where the tank is a share of the way through its move to next
+    fn between(&self, next: &Tank, share: f32) -> Tank {
+        Tank {
+            pos: self.pos + (next.pos - self.pos) * share,
+            rot: self.rot + wrap(next.rot - self.rot) * share,
+            ..*next
+        }
+    }
+
+    fn tank_data(&self, can_fire: bool) -> TankData {
+        TankData {
+            x: self.pos.x().to_string(),
+            y: self.pos.y().to_string(),
+            r: (self.rot * 180f32 / PI).to_string(),
+            can_fire,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct SimBullet {
+    path: Vec<Vec2>,
+    lengths: Vec<f32>,
+    travelled: f32,
+    armed: bool,
+}
+
+impl SimBullet {
+    fn new(trajectory: &BulletTrajectory) -> Self {
+        let path = trajectory.bounces().to_vec();
+        let mut lengths = vec![0f32];
+        for pair in path.windows(2) {
+            let length = (pair[1] - pair[0]).sq_magnitude().sqrt();
+            lengths.push(lengths[lengths.len() - 1] + length);
+        }
+        Self {
+            path,
+            lengths,
+            travelled: 0f32,
+            armed: false,
+        }
+    }
+
+    //Position and direction after travelling dist along the bounce chain
+    fn at(&self, dist: f32) -> Option<(Vec2, Vec2)> {
+        for i in 1..self.path.len() {
+            if dist <= self.lengths[i] {
+                let length = self.lengths[i] - self.lengths[i - 1];
+                if length <= 0f32 {
+                    continue;
+                }
+                let dir = (self.path[i] - self.path[i - 1]) / length;
+                return Some((self.path[i - 1] + dir * (dist - self.lengths[i - 1]), dir));
+            }
+        }
+        None
+    }
+}
+
+pub struct Simulator {
+    map: Arc<Map>,
+    tanks: [Tank; 2],
+    bullets: [Option<SimBullet>; 2],
+    time: f32,
+    outcome: Outcome,
+}
+
+impl Simulator {
+    pub fn new(map: Arc<Map>, spawn_a: Vec2, spawn_b: Vec2) -> Self {
+        Self {
+            map,
+            tanks: [Tank::new(spawn_a, 0f32), Tank::new(spawn_b, PI)],
+            bullets: [None, None],
+            time: 0f32,
+            outcome: Outcome::Running,
+        }
+    }
+
+    //Closest position to preferred where a tank fits
+    pub fn clear_spawn(map: &Map, preferred: Vec2) -> Vec2 {
+        let mut radius = 0f32;
+        while radius < SPAWN_SEARCH {
+            let samples = 1 + (radius / 10f32) as usize * 4;
+            for i in 0..samples {
+                let pos = preferred + Vec2::from_angle(i as f32 / samples as f32 * PI * 2f32) * radius;
+                if !map.blocks_tank(pos, 0f32) {
+                    return pos;
+                }
+            }
+            radius += 10f32;
+        }
+        preferred
+    }
+
+    fn index(team: Team) -> usize {
+        match team {
+            Team::A => 0,
+            Team::B => 1,
+        }
+    }
+
+    pub fn tank(&self, team: Team) -> &Tank {
+        &self.tanks[Self::index(team)]
+    }
+
+    pub fn can_fire(&self, team: Team) -> bool {
+        let index = Self::index(team);
+        self.tanks[index].reload <= 0f32 && self.bullets[index].is_none()
+    }
+
+    pub fn time(&self) -> f32 {
+        self.time
+    }
+
+    pub fn outcome(&self) -> Outcome {
+        self.outcome
+    }
+
+    pub fn game_data(&self) -> GameData {
+        let mut projectiles = HashMap::new();
+        for (index, bullet) in self.bullets.iter().enumerate() {
+            if let Some(bullet) = bullet {
+                if let Some((pos, dir)) = bullet.at(bullet.travelled) {
+                    let vel = dir * self.map.rules().bullet_speed;
+                    projectiles.insert(format!("p{}", index), Projectile {
+                        x: pos.x().to_string(),
+                        y: pos.y().to_string(),
+                        vx: vel.x().round() as i32,
+                        vy: vel.y().round() as i32,
+                    });
+                }
+            }
+        }
+        GameData {
+            tank_a: self.tanks[0].tank_data(self.can_fire(Team::A)),
+            tank_b: self.tanks[1].tank_data(self.can_fire(Team::B)),
+            projectiles,
+        }
+    }
+
+    pub fn step(&mut self, action_a: OutputAction, action_b: OutputAction, dt: f32) -> Outcome {
+        if self.outcome != Outcome::Running {
+            return self.outcome;
+        }
+        self.time += dt;
+        let before = self.tanks;
+        for (index, action) in [action_a, action_b].iter().enumerate() {
+            self.drive(index, *action, dt);
+        }
+
+        let mut hit = [false, false];
+        for owner in 0..2 {
+            let bullet = match &mut self.bullets[owner] {
+                Some(bullet) => bullet,
+                None => continue
+            };
+            let rules = *self.map.rules();
+            let start = bullet.travelled;
+            bullet.travelled = (start + rules.bullet_speed * dt).min(rules.max_dist());
+            let mut dist = start;
+            let mut alive = true;
+            while dist < bullet.travelled {
+                dist = (dist + HIT_STEP).min(bullet.travelled);
+                let pos = match bullet.at(dist) {
+                    Some((pos, _)) => pos,
+                    None => {
+                        alive = false;
+                        break;
+                    }
+                };
+                //Tanks are checked where they were when the bullet got there
+                let share = (dist - start) / (bullet.travelled - start);
+                let tanks = [before[0].between(&self.tanks[0], share), before[1].between(&self.tanks[1], share)];
+                let own_hull = tanks[owner].hull(rules.hull_extents());
+                if !bullet.armed && !own_hull.intersects(pos) {
+                    bullet.armed = true;
+                }
+                if bullet.armed && own_hull.intersects(pos) {
+                    hit[owner] = true;
+                    alive = false;
+                    break;
+                }
+                if tanks[1 - owner].hull(rules.hull_extents()).intersects(pos) {
+                    hit[1 - owner] = true;
+                    alive = false;
+                    break;
+                }
+            }
+            if !alive || bullet.travelled >= rules.max_dist() {
+                self.bullets[owner] = None;
+            }
+        }
+
+        self.outcome = match (hit[0], hit[1]) {
+            (true, true) => Outcome::Draw,
+            (true, false) => Outcome::Won(Team::B),
+            (false, true) => Outcome::Won(Team::A),
+            (false, false) => Outcome::Running,
+        };
+        self.outcome
+    }
+
+    fn drive(&mut self, index: usize, action: OutputAction, dt: f32) {
+        let tank = self.tanks[index];
+        let rules = *self.map.rules();
+        let rot = tank.rot - (action.r as f32).max(-1f32).min(1f32) * rules.turn_rate * dt;
+        let rot = if self.map.blocks_tank(tank.pos, rot) { tank.rot } else { rot };
+        let speed = (action.m as f32).max(-1f32).min(1f32) * rules.tank_speed;
+        let pos = tank.pos + Vec2::from_angle(-rot) * (speed * dt);
+        let pos = if self.map.blocks_tank(pos, rot) { tank.pos } else { pos };
+
+        let can_fire = tank.reload <= 0f32 && self.bullets[index].is_none();
+        let tank = &mut self.tanks[index];
+        tank.rot = rot;
+        tank.pos = pos;
+        tank.reload = (tank.reload - dt).max(0f32);
+        if action.f != 0 && can_fire {
+            let vel = tank.forward() * rules.bullet_speed;
+            let trajectory = self.map.get_bullet_trajectory(
+                Bullet::new_v(tank.pos, vel),
+                rules.max_bounces,
+            );
+            self.bullets[index] = Some(SimBullet::new(&trajectory));
+            tank.reload = rules.fire_cooldown;
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct MatchResult {
+    pub outcome: Outcome,
+    pub time: f32,
+    pub ticks: usize,
+}
+
+//Plays two strategies against each other until someone is hit or max_time runs out
+pub fn run_match(map: Arc<Map>,
+                 strategy_a: Box<dyn Strategy>,
+                 strategy_b: Box<dyn Strategy>,
+                 spawn_a: Vec2,
+                 spawn_b: Vec2,
+                 dt: f32,
+                 max_time: f32) -> MatchResult {
+    //Both bots play by the map's rules, there is nothing to infer
+    let env_a = Arc::new(Mutex::new(Env::with_rules(10, *map.rules(), false)));
+    let env_b = Arc::new(Mutex::new(Env::with_rules(10, *map.rules(), false)));
+    let mut sim = Simulator::new(map, spawn_a, spawn_b);
+    let mut controller_a = Controller::with_strategy(env_a.clone(), strategy_a, Team::A);
+    let mut controller_b = Controller::with_strategy(env_b.clone(), strategy_b, Team::B);
+
+    let mut ticks = 0;
+    while sim.outcome() == Outcome::Running && sim.time() < max_time {
+        let game_data = sim.game_data();
+        env_a.lock().unwrap().update(sim.time(), &game_data.tank_a, &game_data.tank_b, game_data.projectiles.clone())
+            .expect("simulator produced malformed game data");
+        env_b.lock().unwrap().update(sim.time(), &game_data.tank_b, &game_data.tank_a, game_data.projectiles)
+            .expect("simulator produced malformed game data");
+        controller_a.update();
+        controller_b.update();
+        sim.step(controller_a.out_data(), controller_b.out_data(), dt);
+        ticks += 1;
+    }
+    let outcome = if sim.outcome() == Outcome::Running { Outcome::Draw } else { sim.outcome() };
+    MatchResult {
+        outcome,
+        time: sim.time(),
+        ticks,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::r_core::controller::strategy;
+    use crate::r_core::rules::GameRules;
+
+    const FIRE: OutputAction = OutputAction {
+        m: 0,
+        r: 0,
+        f: 1,
+    };
+
+    #[test]
+    fn plays_a_match_to_a_winner() {
+        let map = Arc::new(Map::map_from_file("./maps/MapB.json"));
+        let result = run_match(
+            map.clone(),
+            strategy::by_name("minimum_danger", map.clone()).unwrap(),
+            strategy::by_name("idle", map).unwrap(),
+            Vec2::new(800f32, 320f32),
+            Vec2::new(1200f32, 320f32),
+            1f32 / 30f32,
+            10f32,
+        );
+        assert_eq!(result.outcome, Outcome::Won(Team::A));
+        assert!(result.time < 10f32);
+        assert!((result.time - result.ticks as f32 / 30f32).abs() < 1e-3f32);
+    }
+
+    #[test]
+    fn a_bullet_ends_the_match_at_the_hull() {
+        let map = Arc::new(Map::new(vec![]));
+        let mut sim = Simulator::new(map.clone(), Vec2::new(300f32, 360f32), Vec2::new(700f32, 360f32));
+        let dt = 1f32 / 60f32;
+        sim.step(FIRE, OutputAction::new(), dt);
+        let projectile = &sim.game_data().projectiles["p0"];
+        assert_eq!((projectile.vx, projectile.vy), (400, 0));
+        while sim.step(OutputAction::new(), OutputAction::new(), dt) == Outcome::Running {
+            assert!(sim.time() < 2f32);
+        }
+        assert_eq!(sim.outcome(), Outcome::Won(Team::A));
+        //From the center of one tank to the near face of the other
+        let flight = (400f32 - map.rules().hull_extents().x()) / map.rules().bullet_speed;
+        assert!(sim.time() >= flight && sim.time() < flight + dt * 2f32, "{}", sim.time());
+        //Nothing moves once the match is over
+        assert_eq!(sim.step(FIRE, FIRE, dt), Outcome::Won(Team::A));
+        assert!(sim.time() < flight + dt * 2f32);
+    }
+
+    #[test]
+    fn a_tank_cannot_catch_its_own_bullet() {
+        let map = Arc::new(Map::new(vec![]));
+        let mut sim = Simulator::new(map, Vec2::new(300f32, 360f32), Vec2::new(900f32, 600f32));
+        let dt = 1f32 / 30f32;
+        sim.step(OutputAction { m: 1, r: 0, f: 1 }, OutputAction::new(), dt);
+        //Backing out of the bullet's way arms it, driving on would run into it again
+        for tick in 0..30 {
+            let m = if tick % 2 == 0 { 1 } else { -1 };
+            assert_eq!(sim.step(OutputAction { m, r: 0, f: 0 }, OutputAction::new(), dt), Outcome::Running);
+        }
+    }
+
+    #[test]
+    fn fires_again_after_the_cooldown() {
+        let rules = GameRules {
+            bullet_lifetime: 0.25f32,
+            fire_cooldown: 0.5f32,
+            ..GameRules::default()
+        };
+        let map = Arc::new(Map::new(vec![]).with_rules(rules));
+        let mut sim = Simulator::new(map, Vec2::new(300f32, 360f32), Vec2::new(900f32, 360f32));
+        let dt = 0.05f32;
+        assert!(sim.can_fire(Team::A) && sim.game_data().tank_a.can_fire);
+        sim.step(FIRE, OutputAction::new(), dt);
+        assert!(!sim.can_fire(Team::A) && !sim.game_data().tank_a.can_fire);
+        assert!(sim.can_fire(Team::B));
+        //The bullet is gone after its lifetime, the cooldown still holds
+        while sim.time() < 0.4f32 {
+            sim.step(FIRE, OutputAction::new(), dt);
+        }
+        assert!(sim.game_data().projectiles.is_empty());
+        assert!(!sim.can_fire(Team::A));
+        while sim.time() < 0.6f32 {
+            sim.step(OutputAction::new(), OutputAction::new(), dt);
+        }
+        assert!(sim.can_fire(Team::A) && sim.game_data().tank_a.can_fire);
+        sim.step(FIRE, OutputAction::new(), dt);
+        assert!(sim.game_data().projectiles.contains_key("p0"));
+    }
+}
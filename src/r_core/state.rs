@@ -1,5 +1,20 @@
-use crate::{TankData, Projectile};
+use crate::r_core::protocol::{TankData, Projectile};
 use crate::r_core::math::vec2::Vec2;
+use crate::r_core::error::Error;
+use crate::r_core::kinematics::{Kinematics, wrap};
+
+//How much each new frame moves the motion estimates
+const MOTION_SMOOTHING: f32 = 0.5f32;
+
+fn parse(field: &'static str, value: &str) -> Result<f32, Error> {
+    match value.parse::<f32>() {
+        Ok(parsed) if parsed.is_finite() => Ok(parsed),
+        _ => Err(Error::Parse {
+            field,
+            value: value.to_string(),
+        })
+    }
+}
 
 #[derive(Debug, Copy, Clone)]
 pub struct BotData {
@@ -9,11 +24,21 @@ pub struct BotData {
 }
 
 impl BotData {
-    pub fn from_tank_data(data: &TankData) -> Self {
-        BotData {
-            pos: Vec2::new(data.x.parse::<f32>().unwrap(), data.y.parse::<f32>().unwrap()),
-            r: data.r.parse::<f32>().unwrap() * std::f32::consts::PI / 180f32,
+    pub fn from_tank_data(data: &TankData) -> Result<Self, Error> {
+        Ok(BotData {
+            pos: Vec2::new(parse("tank x", &data.x)?, parse("tank y", &data.y)?),
+            r: parse("tank r", &data.r)? * std::f32::consts::PI / 180f32,
             can_fire: data.can_fire,
+        })
+    }
+
+    //amount of the way from self to other, turning the short way round. can_fire is
+    //self's until other is reached.
+    pub fn lerp(&self, other: &BotData, amount: f32) -> Self {
+        Self {
+            pos: self.pos + (other.pos - self.pos) * amount,
+            r: self.r + wrap(other.r - self.r) * amount,
+            can_fire: if amount < 1f32 { self.can_fire } else { other.can_fire },
         }
     }
 }
@@ -25,13 +50,13 @@ pub struct Bullet {
 }
 
 impl Bullet {
-    pub fn from_projectile(projectile: &Projectile) -> Self {
-        Self::new(
-            projectile.x.parse::<f32>().unwrap(),
-            projectile.y.parse::<f32>().unwrap(),
+    pub fn from_projectile(projectile: &Projectile) -> Result<Self, Error> {
+        Ok(Self::new(
+            parse("projectile x", &projectile.x)?,
+            parse("projectile y", &projectile.y)?,
             projectile.vx as f32,
             projectile.vy as f32,
-        )
+        ))
     }
 
     pub fn new(x: f32, y: f32, vx: f32, vy: f32) -> Self {
@@ -61,25 +86,130 @@ impl Bullet {
     }
 }
 
+//How a tank has been moving up to a frame, estimated from the frames before it
+#[derive(Debug, Copy, Clone)]
+pub struct TankMotion {
+    pub vel: Vec2,
+    //Along the heading, negative when reversing
+    pub speed: f32,
+    //Positive increases the heading
+    pub angular_vel: f32,
+    //Change of speed per second
+    pub acceleration: f32,
+    //Time of the frame it was estimated at
+    pub time: f32,
+}
+
+impl TankMotion {
+    pub fn still(time: f32) -> Self {
+        Self {
+            vel: Vec2::new(0f32, 0f32),
+            speed: 0f32,
+            angular_vel: 0f32,
+            acceleration: 0f32,
+            time,
+        }
+    }
+
+    //Blends the movement between the frame this was estimated at and a later one into it
+    pub fn update(&self, from: &BotData, to: &BotData, time: f32) -> Self {
+        let dt = time - self.time;
+        if dt <= 0f32 {
+            return *self;
+        }
+        let (speed, turn) = Kinematics::measure(from, to, dt);
+        let vel = (to.pos - from.pos) / dt;
+        let blend = |estimate: f32, sample: f32| estimate + (sample - estimate) * MOTION_SMOOTHING;
+        let smoothed = blend(self.speed, speed);
+        Self {
+            vel: self.vel + (vel - self.vel) * MOTION_SMOOTHING,
+            speed: smoothed,
+            angular_vel: blend(self.angular_vel, turn),
+            acceleration: blend(self.acceleration, (smoothed - self.speed) / dt),
+            time,
+        }
+    }
+}
+
 #[derive(Debug, Copy, Clone)]
 pub struct State {
     pub bot: BotData,
     pub opp: BotData,
     pub dist: f32,
     pub abs_bearing: f32,
+    pub time: f32,
+    //Filled in by Env from the frames before, still for a lone state
+    pub bot_motion: TankMotion,
+    pub opp_motion: TankMotion,
 }
 
 impl State {
-    pub fn new(bot: &TankData, opp: &TankData) -> Self {
-        let bot = BotData::from_tank_data(bot);
-        let opp = BotData::from_tank_data(opp);
+    pub fn new(bot: &TankData, opp: &TankData, time: f32) -> Result<Self, Error> {
+        let bot = BotData::from_tank_data(bot)?;
+        let opp = BotData::from_tank_data(opp)?;
         let dist = (bot.pos - opp.pos).sq_magnitude().sqrt();
         let abs_bearing = (opp.pos - bot.pos).angle();
-        Self {
+        Ok(Self {
             bot,
             opp,
             dist,
             abs_bearing,
+            time,
+            bot_motion: TankMotion::still(time),
+            opp_motion: TankMotion::still(time),
+        })
+    }
+
+    //The state amount of the way from self to a later one. The motion estimates are the later
+    //state's, they cover the movement in between.
+    pub fn lerp(&self, other: &State, amount: f32) -> Self {
+        let bot = self.bot.lerp(&other.bot, amount);
+        let opp = self.opp.lerp(&other.opp, amount);
+        Self {
+            bot,
+            opp,
+            dist: (bot.pos - opp.pos).sq_magnitude().sqrt(),
+            abs_bearing: (opp.pos - bot.pos).angle(),
+            time: self.time + (other.time - self.time) * amount,
+            bot_motion: other.bot_motion,
+            opp_motion: other.opp_motion,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tank(x: &str, r: &str) -> TankData {
+        TankData {
+            x: x.to_string(),
+            y: "100".to_string(),
+            r: r.to_string(),
+            can_fire: true,
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn malformed_numbers_name_the_field() {
+        assert!(BotData::from_tank_data(&tank("100.5", "-90")).is_ok());
+        match BotData::from_tank_data(&tank("left", "0")) {
+            Err(Error::Parse { field, value }) => assert_eq!((field, value.as_str()), ("tank x", "left")),
+            other => panic!("{:?}", other),
+        }
+        match BotData::from_tank_data(&tank("100", "NaN")) {
+            Err(Error::Parse { field, value }) => assert_eq!((field, value.as_str()), ("tank r", "NaN")),
+            other => panic!("{:?}", other),
+        }
+        let projectile = Projectile {
+            x: "10".to_string(),
+            y: "inf".to_string(),
+            vx: 0,
+            vy: 400,
+        };
+        match Bullet::from_projectile(&projectile) {
+            Err(Error::Parse { field, value }) => assert_eq!((field, value.as_str()), ("projectile y", "inf")),
+            other => panic!("{:?}", other),
+        }
+    }
+}